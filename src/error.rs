@@ -0,0 +1,83 @@
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use image::ImageError;
+
+/**
+ * Errors returned by the tiler library
+ */
+#[derive(Debug)]
+pub enum TilerError {
+    /// The source image could not be decoded. `path` is `None` for images loaded from memory.
+    Decode {
+        path: Option<PathBuf>,
+        source: ImageError,
+    },
+    /// A file or directory could not be read or written.
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// An output image could not be encoded.
+    Encode { path: PathBuf, source: ImageError },
+    /// A parameter or input was rejected before any work was done.
+    InvalidParameter(String),
+    /// The info.json could not be serialized.
+    Json(serde_json::Error),
+    /// One or more tiles or sizes failed to write. The pyramid is incomplete.
+    Tiles(Vec<TilerError>),
+}
+
+impl TilerError {
+    // maps an error from saving an image, keeping I/O failures (e.g. a full disk) separate from encoder failures
+    pub(crate) fn from_save(path: &Path, error: ImageError) -> TilerError {
+        match error {
+            ImageError::IoError(source) => TilerError::Io {
+                path: path.to_path_buf(),
+                source,
+            },
+            source => TilerError::Encode {
+                path: path.to_path_buf(),
+                source,
+            },
+        }
+    }
+}
+
+impl fmt::Display for TilerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TilerError::Decode {
+                path: Some(path), ..
+            } => write!(f, "Failed to decode image: {}", path.display()),
+            TilerError::Decode { path: None, .. } => write!(f, "Failed to decode image"),
+            TilerError::Io { path, .. } => write!(f, "I/O error on {}", path.display()),
+            TilerError::Encode { path, .. } => {
+                write!(f, "Failed to encode image: {}", path.display())
+            }
+            TilerError::InvalidParameter(msg) => write!(f, "Invalid parameter: {}", msg),
+            TilerError::Json(_) => write!(f, "Failed to serialize info.json"),
+            TilerError::Tiles(failures) => write!(f, "Failed to write {} tile(s)", failures.len()),
+        }
+    }
+}
+
+impl Error for TilerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TilerError::Decode { source, .. } => Some(source),
+            TilerError::Io { source, .. } => Some(source),
+            TilerError::Encode { source, .. } => Some(source),
+            TilerError::InvalidParameter(_) => None,
+            TilerError::Json(source) => Some(source),
+            TilerError::Tiles(failures) => failures.first().map(|e| e as &(dyn Error + 'static)),
+        }
+    }
+}
+
+impl From<serde_json::Error> for TilerError {
+    fn from(error: serde_json::Error) -> Self {
+        TilerError::Json(error)
+    }
+}
@@ -1,6 +1,201 @@
-use anyhow::Error;
-use image::{DynamicImage, ImageReader};
-use std::{fs::File, io::BufReader, path::Path};
+use crate::error::TilerError;
+use crate::streaming::decode_error;
+use image::{
+    codecs::gif::GifDecoder, imageops, metadata::Orientation, AnimationDecoder, DynamicImage,
+    Frames, GenericImageView, ImageBuffer, ImageDecoder, ImageError, ImageFormat, ImageReader,
+    Limits, SubImage,
+};
+use log::warn;
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Cursor, Read, Seek},
+    iter::Peekable,
+    path::{Path, PathBuf},
+};
+use tiff::decoder::{Decoder as TiffDecoder, DecodingResult, Limits as TiffLimits};
+use tiff::tags::{CompressionMethod, PhotometricInterpretation, Tag};
+use tiff::{ColorType as TiffColorType, TiffError, TiffFormatError, TiffUnsupportedError};
+
+// hex digits of the content hash used as an identifier
+const HASH_LENGTH: usize = 16;
+// the brightness Reinhard tone mapping exposes the scene's average to
+const MIDDLE_GREY: f32 = 0.18;
+// keeps black pixels from sending the log average luminance to zero
+const LUMINANCE_DELTA: f32 = 1e-4;
+// the TIFF tag holding an embedded ICC profile
+const ICC_PROFILE_TAG: u16 = 34675;
+// formats image can't recognise from their contents, so the extension is trusted for them
+const FORMATS_WITHOUT_MAGIC: [ImageFormat; 1] = [ImageFormat::Tga];
+
+/// How the decoded pixels are stored
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum PixelMode {
+    /// Convert every image to 8-bit RGB, dropping any alpha channel.
+    #[default]
+    Rgb8,
+    /// Keep the source's colour type, e.g. RGBA8 or 16-bit grey. Tiles are only converted
+    /// when the output format can't hold them.
+    Preserve,
+}
+
+/// How an image's IIIF identifier is worked out from its file
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub enum IdentifierStrategy {
+    /// The file name without its extension, e.g. `page1` for `a/page1.jpg`.
+    #[default]
+    FileStem,
+    /// The path relative to `root` without the extension, with folders joined by `separator`,
+    /// e.g. `a/page1` or `a_page1`.
+    RelativePath { root: PathBuf, separator: String },
+    /// The start of the SHA-256 of the file's contents, so identical files share an identifier.
+    ContentHash,
+    /// A template such as `{parent}_{stem}`. The placeholders are `{stem}`, `{parent}` (the
+    /// folder's name), `{ext}` and `{hash}`.
+    Template(String),
+    /// Identifiers listed by file, e.g. read with [`IdentifierStrategy::from_csv`]. Files are
+    /// looked up by their path and then by their name.
+    Mapping(HashMap<PathBuf, String>),
+}
+
+impl IdentifierStrategy {
+    /// Reads a mapping from a CSV of file paths and identifiers. A `file,id` header row is optional.
+    pub fn from_csv<P: AsRef<Path>>(csv_path: P) -> Result<IdentifierStrategy, TilerError> {
+        let csv_path = csv_path.as_ref();
+        let invalid = |e: csv::Error| {
+            TilerError::InvalidParameter(format!(
+                "Invalid identifier mapping {}: {}",
+                csv_path.display(),
+                e
+            ))
+        };
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .trim(csv::Trim::All)
+            .from_path(csv_path)
+            .map_err(invalid)?;
+        let mut mapping = HashMap::new();
+        for (row, record) in reader.records().enumerate() {
+            let record = record.map_err(invalid)?;
+            match (record.get(0), record.get(1)) {
+                (Some("file"), Some("id")) if row == 0 => {}
+                (Some(file), Some(id)) if !id.is_empty() => {
+                    mapping.insert(PathBuf::from(file), id.to_string());
+                }
+                _ => {
+                    return Err(TilerError::InvalidParameter(format!(
+                        "Invalid identifier mapping {}: row {} needs a file and an id",
+                        csv_path.display(),
+                        row + 1
+                    )))
+                }
+            }
+        }
+        Ok(IdentifierStrategy::Mapping(mapping))
+    }
+
+    /// Works out the identifier for the image at `file_path`.
+    pub fn identify(&self, file_path: &Path) -> Result<String, TilerError> {
+        match self {
+            IdentifierStrategy::FileStem => id_from_path(file_path),
+            IdentifierStrategy::RelativePath { root, separator } => {
+                let relative = file_path.strip_prefix(root).unwrap_or(file_path);
+                let mut parts = Vec::new();
+                if let Some(parent) = relative.parent() {
+                    for component in parent.components() {
+                        parts.push(component.as_os_str().to_string_lossy().into_owned());
+                    }
+                }
+                parts.push(id_from_path(file_path)?);
+                Ok(parts.join(separator))
+            }
+            IdentifierStrategy::ContentHash => content_hash(file_path),
+            IdentifierStrategy::Template(template) => {
+                let parent = file_path
+                    .parent()
+                    .and_then(|parent| parent.file_name())
+                    .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+                let ext = file_path
+                    .extension()
+                    .map_or_else(String::new, |ext| ext.to_string_lossy().into_owned());
+                let mut id = template
+                    .replace("{stem}", &id_from_path(file_path)?)
+                    .replace("{parent}", &parent)
+                    .replace("{ext}", &ext);
+                if id.contains("{hash}") {
+                    id = id.replace("{hash}", &content_hash(file_path)?);
+                }
+                if id.contains('{') {
+                    return Err(TilerError::InvalidParameter(format!(
+                        "Unknown placeholder in identifier template: {}",
+                        template
+                    )));
+                }
+                Ok(id)
+            }
+            IdentifierStrategy::Mapping(mapping) => mapping
+                .get(file_path)
+                .or_else(|| {
+                    file_path
+                        .file_name()
+                        .and_then(|name| mapping.get(Path::new(name)))
+                })
+                .cloned()
+                .ok_or_else(|| {
+                    TilerError::InvalidParameter(format!(
+                        "No identifier mapped for {}",
+                        file_path.display()
+                    ))
+                }),
+        }
+    }
+}
+
+/// What's done with the orientation stored in the source's EXIF metadata
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum OrientationMode {
+    /// Rotate and flip the image upright, as viewers show it.
+    #[default]
+    Apply,
+    /// Keep the pixels as they're stored.
+    Ignore,
+}
+
+/// What's done with the source's embedded ICC colour profile
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum IccMode {
+    /// Keep the pixels as they are. The profile is still available from
+    /// [`IIIFImage::icc_profile`], e.g. to embed in the tiles.
+    #[default]
+    Keep,
+    /// Convert the pixels to sRGB, as browsers assume for images without a profile.
+    ConvertToSrgb,
+}
+
+/// How floating point images, e.g. Radiance HDR or OpenEXR, are brought into the range the
+/// output formats can hold. They're always converted to 16-bit sRGB, even with [`PixelMode::Preserve`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ToneMapping {
+    /// Reinhard's global operator, exposing the image so its average brightness is middle grey
+    /// and compressing the highlights instead of clipping them.
+    #[default]
+    Reinhard,
+    /// Clip values above 1, for images that are already exposed for display.
+    Clip,
+}
+
+/// Settings for decoding a source image
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct LoadOptions {
+    pub pixels: PixelMode,
+    pub orientation: OrientationMode,
+    pub icc: IccMode,
+    pub tone_mapping: ToneMapping,
+    /// How the identifier is worked out when opening a file.
+    pub identifier: IdentifierStrategy,
+}
 
 /**
  * This class stores the source image as a DynamicImage and also works out the IIIF image identifier from the filename
@@ -10,23 +205,162 @@ use std::{fs::File, io::BufReader, path::Path};
 pub struct IIIFImage {
     image: DynamicImage,
     id: String,
+    orientation: Orientation,
+    icc_profile: Option<Vec<u8>>,
 }
 
 impl IIIFImage {
+    /// Loads the image at `img_path`, panicking if it can't be read. Use [`IIIFImage::open`] to handle the error instead.
     pub fn new(img_path: &str) -> IIIFImage {
-        let loaded = load_image(img_path);
-        match loaded {
-            Ok((img, id)) => IIIFImage { image: img, id },
+        match IIIFImage::open(img_path) {
+            Ok(image) => image,
             Err(e) => {
                 panic!("Error loading image: {}", e);
             }
         }
     }
 
+    /// Loads the image at `img_path`, using the file stem as the identifier.
+    pub fn open<P: AsRef<Path>>(img_path: P) -> Result<IIIFImage, TilerError> {
+        IIIFImage::open_with_options(img_path, &LoadOptions::default())
+    }
+
+    /// Loads the image at `img_path` with the given decoding and identifier options.
+    pub fn open_with_options<P: AsRef<Path>>(
+        img_path: P,
+        options: &LoadOptions,
+    ) -> Result<IIIFImage, TilerError> {
+        let file_path = img_path.as_ref();
+        let file = File::open(file_path).map_err(|e| TilerError::Io {
+            path: file_path.to_path_buf(),
+            source: e,
+        })?;
+
+        let id = options.identifier.identify(file_path)?;
+
+        let fallback = ImageFormat::from_path(file_path).ok();
+        let (image, orientation, icc_profile) =
+            match load_image(BufReader::new(file), fallback, options) {
+                Ok(loaded) => loaded,
+                // the page reader decodes TIFFs image can't, e.g. palette colours
+                Err(ImageError::Unsupported(_))
+                    if detect_format(file_path) == Some(ImageFormat::Tiff) =>
+                {
+                    let mut pages = IIIFImage::open_pages(file_path, options)?;
+                    pages.set_id(&id);
+                    if let Some(page) = pages.next() {
+                        let mut page = page?;
+                        page.set_id(&id);
+                        return Ok(page);
+                    }
+                    return Err(TilerError::InvalidParameter(format!(
+                        "No pages in {}",
+                        file_path.display()
+                    )));
+                }
+                Err(e) => {
+                    return Err(TilerError::Decode {
+                        path: Some(file_path.to_path_buf()),
+                        source: e,
+                    })
+                }
+            };
+        Ok(IIIFImage {
+            image,
+            id,
+            orientation,
+            icc_profile,
+        })
+    }
+
+    /// Opens the image at `img_path` to decode each page of a multi-page TIFF or frame of an
+    /// animated GIF as its own image, one at a time from the same decoder. Pages are identified
+    /// as `<id>_p0001`, `<id>_p0002` and so on, unless there's only one. Other formats have a
+    /// single page.
+    pub fn open_pages<P: AsRef<Path>>(
+        img_path: P,
+        options: &LoadOptions,
+    ) -> Result<Pages, TilerError> {
+        let file_path = img_path.as_ref();
+        let id = options.identifier.identify(file_path)?;
+        let source = match detect_format(file_path) {
+            Some(ImageFormat::Tiff) => {
+                let reader = BufReader::new(File::open(file_path).map_err(|e| TilerError::Io {
+                    path: file_path.to_path_buf(),
+                    source: e,
+                })?);
+                PageSource::Tiff(Box::new(open_tiff(file_path, reader)?))
+            }
+            Some(ImageFormat::Gif) => {
+                let reader = BufReader::new(File::open(file_path).map_err(|e| TilerError::Io {
+                    path: file_path.to_path_buf(),
+                    source: e,
+                })?);
+                let decoder = GifDecoder::new(reader).map_err(|e| TilerError::Decode {
+                    path: Some(file_path.to_path_buf()),
+                    source: e,
+                })?;
+                PageSource::Gif(decoder.into_frames().peekable())
+            }
+            _ => PageSource::Single,
+        };
+        Ok(Pages {
+            path: file_path.to_path_buf(),
+            id,
+            options: options.clone(),
+            source,
+            page: 0,
+        })
+    }
+
+    /// Loads an image from any seekable reader, e.g. a network stream that has been buffered.
+    pub fn from_reader<R: Read + Seek>(reader: R, id: &str) -> Result<IIIFImage, TilerError> {
+        IIIFImage::from_reader_with_options(reader, id, &LoadOptions::default())
+    }
+
+    /// Loads an image from a seekable reader with the given decoding options.
+    pub fn from_reader_with_options<R: Read + Seek>(
+        reader: R,
+        id: &str,
+        options: &LoadOptions,
+    ) -> Result<IIIFImage, TilerError> {
+        let (image, orientation, icc_profile) = load_image(BufReader::new(reader), None, options)
+            .map_err(|e| TilerError::Decode {
+            path: None,
+            source: e,
+        })?;
+        Ok(IIIFImage {
+            image,
+            id: id.to_string(),
+            orientation,
+            icc_profile,
+        })
+    }
+
+    /// Loads an image from an in-memory encoded buffer.
+    pub fn from_bytes(bytes: &[u8], id: &str) -> Result<IIIFImage, TilerError> {
+        IIIFImage::from_reader(Cursor::new(bytes), id)
+    }
+
     pub fn id(&self) -> String {
         self.id.clone()
     }
 
+    /// Replaces the identifier, e.g. with one that includes the image's folder.
+    pub fn set_id(&mut self, id: &str) {
+        self.id = id.to_string();
+    }
+
+    /// The orientation the source's EXIF metadata gave, whether or not it was applied.
+    pub fn orientation(&self) -> Orientation {
+        self.orientation
+    }
+
+    /// The source's embedded ICC profile, whether or not the pixels were converted from it.
+    pub fn icc_profile(&self) -> Option<&[u8]> {
+        self.icc_profile.as_deref()
+    }
+
     pub fn get_width(&self) -> i32 {
         self.image.width() as i32
     }
@@ -35,9 +369,19 @@ impl IIIFImage {
         self.image.height() as i32
     }
 
+    /// Returns a copy of the decoded image. Use [`IIIFImage::image`] to borrow it instead.
     pub fn get_image(&self) -> DynamicImage {
         self.image.clone()
     }
+
+    pub fn image(&self) -> &DynamicImage {
+        &self.image
+    }
+
+    /// Borrows a region of the image without copying any pixels.
+    pub fn view(&self, x: u32, y: u32, width: u32, height: u32) -> SubImage<&DynamicImage> {
+        imageops::crop_imm(&self.image, x, y, width, height)
+    }
 }
 
 // Implement Clone for IIIFImage
@@ -46,34 +390,748 @@ impl Clone for IIIFImage {
         IIIFImage {
             image: self.image.clone(),
             id: self.id.clone(),
+            orientation: self.orientation,
+            icc_profile: self.icc_profile.clone(),
+        }
+    }
+}
+
+/// The identifier of a page of a multi-page image, e.g. `scan_p0001` for the first page of `scan`.
+/// Pages are numbered from 1.
+pub fn page_id(id: &str, page: usize) -> String {
+    format!("{}_p{:04}", id, page)
+}
+
+/// The pages of a multi-page image, decoded one at a time. See [`IIIFImage::open_pages`].
+/// Iteration stops after a page fails to decode.
+pub struct Pages {
+    path: PathBuf,
+    id: String,
+    options: LoadOptions,
+    source: PageSource,
+    // pages decoded so far
+    page: usize,
+}
+
+enum PageSource {
+    Tiff(Box<TiffDecoder<BufReader<File>>>),
+    Gif(Peekable<Frames<'static>>),
+    Single,
+    Failed,
+}
+
+impl Iterator for Pages {
+    type Item = Result<IIIFImage, TilerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let decoded = match &mut self.source {
+            PageSource::Single if self.page > 0 => return None,
+            PageSource::Single => {
+                self.page += 1;
+                let image = IIIFImage::open_with_options(&self.path, &self.options);
+                return Some(image.map(|mut image| {
+                    image.set_id(&self.id);
+                    image
+                }));
+            }
+            PageSource::Failed => return None,
+            PageSource::Tiff(decoder) => {
+                if self.page > 0 {
+                    if !decoder.more_images() {
+                        return None;
+                    }
+                    if let Err(e) = decoder.next_image() {
+                        self.source = PageSource::Failed;
+                        return Some(Err(decode_error(&self.path, ImageFormat::Tiff, e)));
+                    }
+                }
+                read_tiff_page(&self.path, decoder, self.page)
+                    .map(|page| (page, !decoder.more_images()))
+            }
+            PageSource::Gif(frames) => {
+                let frame = frames.next()?;
+                // frames are composited onto the whole canvas, so each one stands alone
+                let last = frames.peek().is_none();
+                frame
+                    .map(|frame| {
+                        let image = DynamicImage::ImageRgba8(frame.into_buffer());
+                        ((image, Orientation::NoTransforms, None), last)
+                    })
+                    .map_err(|e| TilerError::Decode {
+                        path: Some(self.path.clone()),
+                        source: e,
+                    })
+            }
+        };
+        self.page += 1;
+        match decoded {
+            Ok(((image, orientation, icc_profile), last)) => {
+                let id = if self.page == 1 && last {
+                    self.id.clone()
+                } else {
+                    page_id(&self.id, self.page)
+                };
+                Some(Ok(IIIFImage {
+                    image: finish_image(image, orientation, icc_profile.as_deref(), &self.options),
+                    id,
+                    orientation,
+                    icc_profile,
+                }))
+            }
+            Err(e) => {
+                self.source = PageSource::Failed;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl Pages {
+    /// Replaces the identifier the pages' identifiers are made from.
+    pub fn set_id(&mut self, id: &str) {
+        self.id = id.to_string();
+    }
+}
+
+// decodes the TIFF page the decoder is on, with its orientation and ICC profile, within the
+// same memory limits as load_image
+fn read_tiff_page(
+    file_path: &Path,
+    decoder: &mut TiffDecoder<BufReader<File>>,
+    page: usize,
+) -> Result<(DynamicImage, Orientation, Option<Vec<u8>>), TilerError> {
+    let tiff_error = |e| decode_error(file_path, ImageFormat::Tiff, e);
+    let (width, height) = decoder.dimensions().map_err(tiff_error)?;
+    // unreadable metadata shouldn't stop the pixels being used
+    let orientation = decoder
+        .find_tag_unsigned::<u8>(Tag::Orientation)
+        .ok()
+        .flatten()
+        .and_then(Orientation::from_exif)
+        .unwrap_or(Orientation::NoTransforms);
+    let icc_profile = decoder
+        .find_tag_unsigned_vec::<u8>(Tag::Unknown(ICC_PROFILE_TAG))
+        .ok()
+        .flatten();
+    let photometric = decoder
+        .find_tag_unsigned::<u16>(Tag::PhotometricInterpretation)
+        .map_err(tiff_error)?;
+    if photometric == Some(PhotometricInterpretation::RGBPalette.to_u16()) {
+        let image = read_palette_page(file_path, decoder, page, (width, height))?;
+        return Ok((image, orientation, icc_profile));
+    }
+
+    let color_type = decoder.colortype().map_err(tiff_error)?;
+    let (samples, bits) = match color_type {
+        TiffColorType::Gray(bits) => (1, bits),
+        TiffColorType::GrayA(bits) => (2, bits),
+        TiffColorType::RGB(bits) | TiffColorType::YCbCr(bits) => (3, bits),
+        TiffColorType::RGBA(bits) | TiffColorType::CMYK(bits) => (4, bits),
+        TiffColorType::Palette(bits) => (1, bits),
+    };
+    reserve_tiff_page(file_path, (width, height), samples * u64::from(bits))?;
+    let compression = decoder
+        .find_tag_unsigned::<u16>(Tag::Compression)
+        .map_err(tiff_error)?;
+    let image = match (color_type, decoder.read_image().map_err(tiff_error)?) {
+        (TiffColorType::Gray(1), DecodingResult::U8(data)) => {
+            // bilevel rows are packed eight pixels to a byte
+            let stride = width.div_ceil(8) as usize;
+            Some(DynamicImage::ImageLuma8(ImageBuffer::from_fn(
+                width,
+                height,
+                |x, y| {
+                    let byte = data[y as usize * stride + x as usize / 8];
+                    image::Luma([((byte >> (7 - x % 8)) & 1) * 255])
+                },
+            )))
+        }
+        (TiffColorType::Gray(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma8)
+        }
+        (TiffColorType::Gray(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma16)
+        }
+        (TiffColorType::GrayA(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA8)
+        }
+        (TiffColorType::GrayA(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA16)
         }
+        (TiffColorType::RGB(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb8)
+        }
+        // the JPEG decoder converts YCbCr to RGB, other compressions leave it subsampled
+        (TiffColorType::YCbCr(8), DecodingResult::U8(data))
+            if compression == Some(CompressionMethod::ModernJPEG.to_u16()) =>
+        {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb8)
+        }
+        (TiffColorType::RGB(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb16)
+        }
+        (TiffColorType::RGB(32), DecodingResult::F32(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb32F)
+        }
+        (TiffColorType::RGBA(8), DecodingResult::U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba8)
+        }
+        (TiffColorType::RGBA(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba16)
+        }
+        (TiffColorType::RGBA(32), DecodingResult::F32(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba32F)
+        }
+        (TiffColorType::CMYK(8), DecodingResult::U8(data)) => {
+            let rgb = data
+                .chunks_exact(4)
+                .flat_map(|cmyk| {
+                    let white = 255 - u16::from(cmyk[3]);
+                    [cmyk[0], cmyk[1], cmyk[2]]
+                        .map(|ink| ((255 - u16::from(ink)) * white / 255) as u8)
+                })
+                .collect();
+            ImageBuffer::from_raw(width, height, rgb).map(DynamicImage::ImageRgb8)
+        }
+        _ => None,
+    };
+    let image = image.ok_or_else(|| {
+        tiff_error(TiffError::UnsupportedError(
+            TiffUnsupportedError::UnsupportedColorType(color_type),
+        ))
+    })?;
+    Ok((image, orientation, icc_profile))
+}
+
+// the tiff crate can't decode palette colours, so the page's indices are decoded from a copy of
+// the file marked as grayscale and looked up in its colour map
+fn read_palette_page(
+    file_path: &Path,
+    decoder: &mut TiffDecoder<BufReader<File>>,
+    page: usize,
+    (width, height): (u32, u32),
+) -> Result<DynamicImage, TilerError> {
+    let tiff_error = |e| decode_error(file_path, ImageFormat::Tiff, e);
+    let unsupported = |message: &str| {
+        tiff_error(TiffError::FormatError(TiffFormatError::Format(format!(
+            "{} in palette image",
+            message
+        ))))
+    };
+    let bits = decoder
+        .find_tag_unsigned::<u8>(Tag::BitsPerSample)
+        .map_err(tiff_error)?
+        .unwrap_or(1);
+    if !matches!(bits, 1 | 2 | 4 | 8) {
+        return Err(unsupported("Unsupported bits per sample"));
+    }
+    let color_map = decoder.get_tag_u16_vec(Tag::ColorMap).map_err(tiff_error)?;
+    let entries = 1usize << bits;
+    if color_map.len() != 3 * entries {
+        return Err(unsupported("Wrong colour map size"));
+    }
+
+    // the tiff crate only reads the indices of a page it takes for greyscale, so it's reopened
+    // with the page's photometric interpretation read as BlackIsZero
+    let (offset, big_endian) = photometric_offset(decoder, page)
+        .map_err(|e| tiff_error(TiffError::IoError(e)))?
+        .ok_or_else(|| unsupported("Unreadable directory"))?;
+    let file = File::open(file_path).map_err(|e| TilerError::Io {
+        path: file_path.to_path_buf(),
+        source: e,
+    })?;
+    let black_is_zero = PhotometricInterpretation::BlackIsZero.to_u16();
+    let black_is_zero = if big_endian {
+        black_is_zero.to_be_bytes()
+    } else {
+        black_is_zero.to_le_bytes()
+    };
+    // the indices and the colours they're looked up as
+    reserve_tiff_page(file_path, (width, height), u64::from(bits) + 24)?;
+    let mut gray = open_tiff(
+        file_path,
+        Patched {
+            inner: BufReader::new(file),
+            position: 0,
+            offset,
+            bytes: black_is_zero,
+        },
+    )?;
+    gray.seek_to_image(page).map_err(tiff_error)?;
+    let DecodingResult::U8(indices) = gray.read_image().map_err(tiff_error)? else {
+        return Err(unsupported("Unexpected sample format"));
+    };
+
+    let stride = (width as usize * bits as usize).div_ceil(8);
+    let bits = bits as usize;
+    let mask = (entries - 1) as u8;
+    Ok(DynamicImage::ImageRgb8(ImageBuffer::from_fn(
+        width,
+        height,
+        |x, y| {
+            // indices narrower than a byte are packed from the high bits down
+            let bit = x as usize * bits;
+            let byte = indices[y as usize * stride + bit / 8];
+            let index = usize::from((byte >> (8 - bits - bit % 8)) & mask);
+            image::Rgb([0, 1, 2].map(|channel| (color_map[channel * entries + index] >> 8) as u8))
+        },
+    )))
+}
+
+// finds where the value of the page's photometric interpretation is stored and whether the file
+// is big endian, walking the chain of directories with the decoder's reader. Returns `None` if the
+// page has no such tag.
+fn photometric_offset<R: Read + Seek>(
+    decoder: &mut TiffDecoder<R>,
+    page: usize,
+) -> io::Result<Option<(u64, bool)>> {
+    decoder.goto_offset_u64(0)?;
+    let big_endian = decoder.read_byte()? == b'M';
+    decoder.goto_offset_u64(2)?;
+    // BigTIFF has wider offsets and counts, and its first directory's offset comes later
+    let big = decoder.read_short()? == 43;
+    let (count_length, entry_length, offset_length) = if big { (8, 20, 8) } else { (2, 12, 4) };
+    let read_at = |decoder: &mut TiffDecoder<R>, offset: u64, length: u64| {
+        decoder.goto_offset_u64(offset)?;
+        match length {
+            2 => decoder.read_short().map(u64::from),
+            4 => decoder.read_long().map(u64::from),
+            _ => decoder.read_long8(),
+        }
+    };
+    let mut directory = read_at(decoder, if big { 8 } else { 4 }, offset_length)?;
+    for _ in 0..page {
+        let entries = read_at(decoder, directory, count_length)?;
+        directory = read_at(
+            decoder,
+            directory + count_length + entries * entry_length,
+            offset_length,
+        )?;
+    }
+    let entries = read_at(decoder, directory, count_length)?;
+    for entry in 0..entries {
+        let start = directory + count_length + entry * entry_length;
+        if read_at(decoder, start, 2)? == u64::from(Tag::PhotometricInterpretation.to_u16()) {
+            // a single short sits at the start of the value field
+            return Ok(Some((start + 4 + offset_length, big_endian)));
+        }
+    }
+    Ok(None)
+}
+
+// a reader that reads `bytes` at `offset` in place of what the inner reader holds there
+struct Patched<R> {
+    inner: R,
+    position: u64,
+    offset: u64,
+    bytes: [u8; 2],
+}
+
+impl<R: Read> Read for Patched<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        for (i, byte) in self.bytes.iter().enumerate() {
+            if let Some(at) = (self.offset + i as u64).checked_sub(self.position) {
+                if at < read as u64 {
+                    buf[at as usize] = *byte;
+                }
+            }
+        }
+        self.position += read as u64;
+        Ok(read)
     }
 }
 
-fn load_image(img_path: &str) -> Result<(DynamicImage, String), Error> {
-    // open the file and create a buffered reader
-    let file = File::open(img_path)?;
-    let reader = BufReader::new(file);
+impl<R: Seek> Seek for Patched<R> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.position = self.inner.seek(pos)?;
+        Ok(self.position)
+    }
+}
 
-    // read in the image and convert it to rbg8
-    let img = ImageReader::new(reader)
-        .with_guessed_format()?
-        .decode()?
-        .into_rgb8();
-    let rgb_img = DynamicImage::ImageRgb8(img);
+// reserves memory for a decoded page within image's default limits, as load_image does
+fn reserve_tiff_page(
+    file_path: &Path,
+    (width, height): (u32, u32),
+    bits_per_pixel: u64,
+) -> Result<(), TilerError> {
+    let limit_error = |e| TilerError::Decode {
+        path: Some(file_path.to_path_buf()),
+        source: e,
+    };
+    let mut limits = Limits::default();
+    limits
+        .check_dimensions(width, height)
+        .map_err(limit_error)?;
+    let total_bytes = (u64::from(width) * u64::from(height)).saturating_mul(bits_per_pixel) / 8;
+    limits.reserve(total_bytes).map_err(limit_error)
+}
 
-    // extract the file name as the image id
-    let file_path = Path::new(img_path);
-    let image_id = file_path
+// opens a TIFF decoder whose buffers are bounded by image's default allocation limit rather than
+// the tiff crate's own, which reserve_tiff_page checks each page against
+fn open_tiff<R: Read + Seek>(file_path: &Path, reader: R) -> Result<TiffDecoder<R>, TilerError> {
+    let max_alloc = Limits::default().max_alloc.unwrap_or(u64::MAX);
+    let max_alloc = usize::try_from(max_alloc).unwrap_or(usize::MAX);
+    let mut limits = TiffLimits::default();
+    limits.decoding_buffer_size = max_alloc;
+    limits.intermediate_buffer_size = max_alloc;
+    limits.ifd_value_size = max_alloc;
+    TiffDecoder::new(reader)
+        .map(|decoder| decoder.with_limits(limits))
+        .map_err(|e| decode_error(file_path, ImageFormat::Tiff, e))
+}
+
+/// Works out an image's identifier from its file name, without the extension.
+pub fn id_from_path(file_path: &Path) -> Result<String, TilerError> {
+    file_path
         .file_stem()
         .and_then(|file_name| file_name.to_str())
         .map(|file_name| file_name.to_string())
         .ok_or_else(|| {
-            Error::msg(format!(
+            TilerError::InvalidParameter(format!(
                 "Failed to extract file name from path: {}",
                 file_path.display()
             ))
-        })?;
+        })
+}
+
+// the first HASH_LENGTH hex digits of the SHA-256 of the file
+fn content_hash(file_path: &Path) -> Result<String, TilerError> {
+    let io_error = |source| TilerError::Io {
+        path: file_path.to_path_buf(),
+        source,
+    };
+    let mut file = File::open(file_path).map_err(io_error)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).map_err(io_error)?;
+    let hex: String = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Ok(hex[..HASH_LENGTH].to_string())
+}
+
+/// Reads the EXIF orientation of the image at `img_path` without decoding its pixels.
+pub fn read_orientation<P: AsRef<Path>>(img_path: P) -> Result<Orientation, TilerError> {
+    let mut decoder = open_decoder(img_path.as_ref())?;
+    Ok(decoder.orientation().unwrap_or(Orientation::NoTransforms))
+}
 
-    Ok((rgb_img, image_id.to_string()))
+/// Reads the width and height of the image at `img_path` without decoding its pixels. With
+/// [`OrientationMode::Apply`] they're swapped for images the EXIF orientation turns on their side.
+pub fn read_dimensions<P: AsRef<Path>>(
+    img_path: P,
+    orientation: OrientationMode,
+) -> Result<(u32, u32), TilerError> {
+    let mut decoder = open_decoder(img_path.as_ref())?;
+    let dimensions = decoder.dimensions();
+    let source_orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    Ok(oriented_dimensions(
+        dimensions,
+        source_orientation,
+        orientation,
+    ))
+}
+
+/// Reads the width and height of each page [`IIIFImage::open_pages`] would decode: every page of
+/// a multi-page TIFF, every frame of an animated GIF and the one page of other images. TIFF
+/// pages are read without decoding their pixels, but GIF frames have to be decoded to count them.
+pub fn read_page_dimensions<P: AsRef<Path>>(
+    img_path: P,
+    orientation: OrientationMode,
+) -> Result<Vec<(u32, u32)>, TilerError> {
+    let file_path = img_path.as_ref();
+    let open = || {
+        File::open(file_path)
+            .map(BufReader::new)
+            .map_err(|e| TilerError::Io {
+                path: file_path.to_path_buf(),
+                source: e,
+            })
+    };
+    match detect_format(file_path) {
+        Some(ImageFormat::Tiff) => {
+            let tiff_error = |e| decode_error(file_path, ImageFormat::Tiff, e);
+            let mut decoder = open_tiff(file_path, open()?)?;
+            let mut pages = Vec::new();
+            loop {
+                let page_orientation = decoder
+                    .find_tag_unsigned::<u8>(Tag::Orientation)
+                    .ok()
+                    .flatten()
+                    .and_then(Orientation::from_exif)
+                    .unwrap_or(Orientation::NoTransforms);
+                let dimensions = decoder.dimensions().map_err(tiff_error)?;
+                pages.push(oriented_dimensions(
+                    dimensions,
+                    page_orientation,
+                    orientation,
+                ));
+                if !decoder.more_images() {
+                    return Ok(pages);
+                }
+                decoder.next_image().map_err(tiff_error)?;
+            }
+        }
+        Some(ImageFormat::Gif) => {
+            let gif_error = |e| TilerError::Decode {
+                path: Some(file_path.to_path_buf()),
+                source: e,
+            };
+            let decoder = GifDecoder::new(open()?).map_err(gif_error)?;
+            let dimensions = decoder.dimensions();
+            let mut pages = Vec::new();
+            // frames are composited onto the whole canvas, so they're all its size
+            for frame in decoder.into_frames() {
+                frame.map_err(gif_error)?;
+                pages.push(dimensions);
+            }
+            Ok(pages)
+        }
+        _ => Ok(vec![read_dimensions(file_path, orientation)?]),
+    }
+}
+
+// swaps the width and height of images the orientation turns on their side, if it's applied
+fn oriented_dimensions(
+    (width, height): (u32, u32),
+    source_orientation: Orientation,
+    orientation: OrientationMode,
+) -> (u32, u32) {
+    let sideways = matches!(
+        source_orientation,
+        Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH
+    );
+    if orientation == OrientationMode::Apply && sideways {
+        (height, width)
+    } else {
+        (width, height)
+    }
+}
+
+/// Reads the embedded ICC profile of the image at `img_path` without decoding its pixels.
+pub fn read_icc_profile<P: AsRef<Path>>(img_path: P) -> Result<Option<Vec<u8>>, TilerError> {
+    let file_path = img_path.as_ref();
+    open_decoder(file_path)?
+        .icc_profile()
+        .map_err(|e| TilerError::Decode {
+            path: Some(file_path.to_path_buf()),
+            source: e,
+        })
+}
+
+/// Works out the format of the image at `img_path` from its contents. Only TGA, which has no
+/// signature, goes by the extension, so a corrupt `.png` isn't taken for a PNG. Returns `None` if
+/// it isn't an image or support for its format wasn't compiled in.
+pub fn detect_format<P: AsRef<Path>>(img_path: P) -> Option<ImageFormat> {
+    let file_path = img_path.as_ref();
+    let file = File::open(file_path).ok()?;
+    guess_format(BufReader::new(file), ImageFormat::from_path(file_path).ok())
+        .ok()?
+        .format()
+        .filter(|format| format.reading_enabled())
+}
+
+// reads the header of the image, working out its format from its contents
+fn open_decoder(file_path: &Path) -> Result<impl ImageDecoder, TilerError> {
+    let file = File::open(file_path).map_err(|e| TilerError::Io {
+        path: file_path.to_path_buf(),
+        source: e,
+    })?;
+    guess_format(BufReader::new(file), ImageFormat::from_path(file_path).ok())
+        .map_err(|e| TilerError::Io {
+            path: file_path.to_path_buf(),
+            source: e,
+        })?
+        .into_decoder()
+        .map_err(|e| TilerError::Decode {
+            path: Some(file_path.to_path_buf()),
+            source: e,
+        })
+}
+
+// works out the format from the magic bytes, using `fallback`, the extension's format, when they
+// don't match any format and it's one without magic bytes
+fn guess_format<R: BufRead + Seek>(
+    reader: R,
+    fallback: Option<ImageFormat>,
+) -> io::Result<ImageReader<R>> {
+    let mut reader = ImageReader::new(reader).with_guessed_format()?;
+    let unsigned = fallback.filter(|format| FORMATS_WITHOUT_MAGIC.contains(format));
+    if let (None, Some(format)) = (reader.format(), unsigned) {
+        reader.set_format(format);
+    }
+    Ok(reader)
+}
+
+// decodes the image, returning it along with the orientation and ICC profile its metadata gave
+fn load_image<R: BufRead + Seek>(
+    reader: R,
+    fallback: Option<ImageFormat>,
+    options: &LoadOptions,
+) -> Result<(DynamicImage, Orientation, Option<Vec<u8>>), image::ImageError> {
+    let mut decoder = guess_format(reader, fallback)?.into_decoder()?;
+    // unreadable metadata shouldn't stop the pixels being used
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let icc_profile = decoder.icc_profile().unwrap_or(None);
+    Limits::default().reserve(decoder.total_bytes())?;
+    let img = DynamicImage::from_decoder(decoder)?;
+    let img = finish_image(img, orientation, icc_profile.as_deref(), options);
+    Ok((img, orientation, icc_profile))
+}
+
+// tone maps, orients, converts the colours and sets the pixel format of a decoded image
+fn finish_image(
+    mut img: DynamicImage,
+    orientation: Orientation,
+    icc_profile: Option<&[u8]>,
+    options: &LoadOptions,
+) -> DynamicImage {
+    if matches!(
+        img,
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+    ) {
+        img = tone_map(&img, options.tone_mapping);
+    }
+    if options.orientation == OrientationMode::Apply {
+        img.apply_orientation(orientation);
+    }
+    if let (IccMode::ConvertToSrgb, Some(icc_profile)) = (options.icc, icc_profile) {
+        match convert_to_srgb(&img, icc_profile) {
+            Ok(converted) => img = converted,
+            Err(e) => warn!(
+                "Keeping the original colours, the ICC profile can't be used: {}",
+                e
+            ),
+        }
+    }
+    match options.pixels {
+        PixelMode::Rgb8 => DynamicImage::ImageRgb8(img.into_rgb8()),
+        PixelMode::Preserve => img,
+    }
+}
+
+// maps linear floating point pixels to 16-bit sRGB, keeping any alpha channel
+fn tone_map(image: &DynamicImage, tone_mapping: ToneMapping) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    let alpha = image.color().has_alpha();
+    let pixels = image.to_rgba32f();
+    let luminance = |[r, g, b, _]: [f32; 4]| 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let exposure = match tone_mapping {
+        ToneMapping::Reinhard => {
+            let log_sum: f64 = pixels
+                .pixels()
+                .map(|pixel| f64::from((LUMINANCE_DELTA + luminance(pixel.0).max(0.0)).ln()))
+                .sum();
+            let log_average =
+                (log_sum / (f64::from(width) * f64::from(height)).max(1.0)).exp() as f32;
+            if log_average.is_finite() && log_average > 0.0 {
+                MIDDLE_GREY / log_average
+            } else {
+                1.0
+            }
+        }
+        ToneMapping::Clip => 1.0,
+    };
+    let channels = if alpha { 4 } else { 3 };
+    let mut mapped = Vec::with_capacity(width as usize * height as usize * channels);
+    for pixel in pixels.pixels() {
+        let gain = match tone_mapping {
+            ToneMapping::Reinhard => exposure / (1.0 + exposure * luminance(pixel.0).max(0.0)),
+            ToneMapping::Clip => exposure,
+        };
+        for &value in &pixel.0[..3] {
+            mapped.push(to_u16(srgb_encode(value * gain)));
+        }
+        if alpha {
+            mapped.push(to_u16(pixel.0[3]));
+        }
+    }
+    if alpha {
+        DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, mapped).unwrap())
+    } else {
+        DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, mapped).unwrap())
+    }
+}
+
+// the sRGB transfer function for a linear value, clipped to 0 to 1
+fn srgb_encode(linear: f32) -> f32 {
+    let linear = linear.clamp(0.0, 1.0);
+    if linear <= 0.003_130_8 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// NaN becomes 0 as the cast saturates
+fn to_u16(value: f32) -> u16 {
+    (value.clamp(0.0, 1.0) * f32::from(u16::MAX)).round() as u16
+}
+
+// converts the pixels from the ICC profile to sRGB, keeping any alpha channel and 16-bit depth.
+// Grey images become RGB.
+fn convert_to_srgb(image: &DynamicImage, icc_profile: &[u8]) -> Result<DynamicImage, String> {
+    let source = ColorProfile::new_from_slice(icc_profile).map_err(|e| e.to_string())?;
+    let srgb = ColorProfile::new_srgb();
+    let alpha = image.color().has_alpha();
+    let deep = image.color().bytes_per_pixel() > image.color().channel_count();
+    let (src_layout, dst_layout) = match (source.color_space, alpha) {
+        (DataColorSpace::Gray, false) => (Layout::Gray, Layout::Rgb),
+        (DataColorSpace::Gray, true) => (Layout::GrayAlpha, Layout::Rgba),
+        (DataColorSpace::Rgb, false) => (Layout::Rgb, Layout::Rgb),
+        (DataColorSpace::Rgb, true) => (Layout::Rgba, Layout::Rgba),
+        (color_space, _) => {
+            return Err(format!(
+                "{:?} profiles can't be applied to decoded images",
+                color_space
+            ))
+        }
+    };
+    let (width, height) = image.dimensions();
+    let options = TransformOptions::default();
+    let invalid = |e: moxcms::CmsError| e.to_string();
+    if deep {
+        let transform = source
+            .create_transform_16bit(src_layout, &srgb, dst_layout, options)
+            .map_err(invalid)?;
+        let pixels = match src_layout {
+            Layout::Gray => image.to_luma16().into_raw(),
+            Layout::GrayAlpha => image.to_luma_alpha16().into_raw(),
+            Layout::Rgb => image.to_rgb16().into_raw(),
+            _ => image.to_rgba16().into_raw(),
+        };
+        let mut converted = vec![0; width as usize * height as usize * dst_layout.channels()];
+        transform
+            .transform(&pixels, &mut converted)
+            .map_err(invalid)?;
+        Ok(if alpha {
+            DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, converted).unwrap())
+        } else {
+            DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, converted).unwrap())
+        })
+    } else {
+        let transform = source
+            .create_transform_8bit(src_layout, &srgb, dst_layout, options)
+            .map_err(invalid)?;
+        let pixels = match src_layout {
+            Layout::Gray => image.to_luma8().into_raw(),
+            Layout::GrayAlpha => image.to_luma_alpha8().into_raw(),
+            Layout::Rgb => image.to_rgb8().into_raw(),
+            _ => image.to_rgba8().into_raw(),
+        };
+        let mut converted = vec![0; width as usize * height as usize * dst_layout.channels()];
+        transform
+            .transform(&pixels, &mut converted)
+            .map_err(invalid)?;
+        Ok(if alpha {
+            DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, converted).unwrap())
+        } else {
+            DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, converted).unwrap())
+        })
+    }
 }
@@ -1,4 +1,8 @@
+use crate::error::TilerError;
 use crate::iiif_image::IIIFImage;
+use crate::output::{fits_formats, OutputFormat};
+use image::DynamicImage;
+use std::collections::HashSet;
 use std::fmt;
 
 /**
@@ -9,15 +13,42 @@ pub struct ImageInfo<'a> {
     _tile_width: i32,
     _tile_height: i32,
     _zoom_levels: i32,
-    _image: &'a IIIFImage,
+    _image: Option<&'a IIIFImage>,
+    _id: String,
+    _width: i32,
+    _height: i32,
     _scale_factors: Vec<i32>,
     _sizes: Vec<(i32, i32)>,
 }
 
 impl<'a> ImageInfo<'a> {
     pub fn new(image: &'a IIIFImage, tile_width: i32, tile_height: i32, zoom_level: i32) -> Self {
+        let mut info = ImageInfo::from_dimensions(
+            &image.id(),
+            image.get_width(),
+            image.get_height(),
+            tile_width,
+            tile_height,
+            zoom_level,
+        );
+        info._image = Some(image);
+        info
+    }
+
+    /// Describes an image that hasn't been decoded, e.g. one that will be tiled by streaming it from disk.
+    pub fn from_dimensions(
+        id: &str,
+        width: i32,
+        height: i32,
+        tile_width: i32,
+        tile_height: i32,
+        zoom_level: i32,
+    ) -> Self {
         let mut info = ImageInfo {
-            _image: image,
+            _image: None,
+            _id: id.to_string(),
+            _width: width,
+            _height: height,
             _tile_width: tile_width,
             _tile_height: tile_height,
             _zoom_levels: zoom_level,
@@ -32,47 +63,85 @@ impl<'a> ImageInfo<'a> {
         self.initialize_image_info();
     }
 
+    /// Picks the tile size (256 to 1280 pixels) and zoom level (1 to 4) that come to fewer than
+    /// `p_max_file_no` files, preferring small tiles and then more zoom levels. If none do, the
+    /// combination with the fewest files is used. Use [`ImageInfo::try_fit_to_max_file_no`] to
+    /// handle that case instead.
     pub fn fit_to_max_file_no(&mut self, p_max_file_no: i32) {
+        if let Err(e) = self.try_fit_to_max_file_no(p_max_file_no) {
+            log::warn!("{}", e);
+            let t_fewest = self
+                .fit_candidates()
+                .into_iter()
+                .min_by_key(|(_, _, file_count)| *file_count);
+            if let Some((t_zoom, t_tile_size, _)) = t_fewest {
+                self.apply_fit(t_zoom, t_tile_size);
+            }
+        }
+    }
+
+    /// Like [`ImageInfo::fit_to_max_file_no`], but leaves the tile size and zoom level alone and
+    /// returns an error if no combination comes to fewer than `p_max_file_no` files. Returns the
+    /// file count of the combination picked.
+    pub fn try_fit_to_max_file_no(&mut self, p_max_file_no: i32) -> Result<i32, TilerError> {
+        let t_candidates = self.fit_candidates();
+        // Find optimal tile size and zoom level
+        for &(t_zoom, tile_size, file_count) in &t_candidates {
+            if file_count < p_max_file_no {
+                log::info!(
+                    "Using TileSize: {} Zoom: {} came back with {} files. Target: {}",
+                    tile_size,
+                    t_zoom,
+                    file_count,
+                    p_max_file_no
+                );
+                self.apply_fit(t_zoom, tile_size);
+                log::info!(
+                    "Found combinations {} with a file count of {}",
+                    self,
+                    file_count
+                );
+                return Ok(file_count);
+            }
+            log::debug!(
+                "Rejected TileSize: {} Zoom: {} came back with {} files. Target: {}",
+                tile_size,
+                t_zoom,
+                file_count,
+                p_max_file_no
+            );
+        }
+        let t_fewest = t_candidates
+            .iter()
+            .map(|(_, _, file_count)| *file_count)
+            .min()
+            .unwrap_or_default();
+        Err(TilerError::InvalidParameter(format!(
+            "{} can't be tiled in fewer than {} files, the fewest is {}",
+            self._id, p_max_file_no, t_fewest
+        )))
+    }
+
+    // every zoom level and tile size fit_to_max_file_no tries, in order, with its file count
+    fn fit_candidates(&self) -> Vec<(i32, i32, i32)> {
         let t_max_zoom = 4;
         let t_max_tile_size_factor = 5;
-        let t_file_count = 0;
-        // Find optimal tile size and zoom level
-        let (t_zoom_selected, t_tile_size) =
-            'outer: loop {
-                for j in 1..=t_max_tile_size_factor {
-                    let tile_size = j * 256;
-
-                    for t_zoom in (1..=t_max_zoom).rev() {
-                        let file_count = self._calculate_file_count(t_zoom, tile_size, tile_size);
-
-                        if file_count < p_max_file_no {
-                            log::info!(
-                                "Using TileSize: {} Zoom: {} came back with {} files. Target: {}",
-                                tile_size,
-                                t_zoom,
-                                file_count,
-                                p_max_file_no
-                            );
-
-                            break 'outer (t_zoom, tile_size);
-                        } else {
-                            log::debug!(
-                            "Rejected TileSize: {} Zoom: {} came back with {} files. Target: {}",
-                            tile_size, t_zoom, file_count, p_max_file_no
-                        );
-                        }
-                    }
-                }
-            };
-        self.set_tile_width(t_tile_size);
-        self.set_tile_height(t_tile_size);
-        self.set_zoom_level(t_zoom_selected);
+        let mut t_candidates = Vec::new();
+        for j in 1..=t_max_tile_size_factor {
+            let tile_size = j * 256;
+            for t_zoom in (1..=t_max_zoom).rev() {
+                let file_count = self._calculate_file_count(t_zoom, tile_size, tile_size);
+                t_candidates.push((t_zoom, tile_size, file_count));
+            }
+        }
+        t_candidates
+    }
+
+    fn apply_fit(&mut self, p_zoom: i32, p_tile_size: i32) {
+        self.set_tile_width(p_tile_size);
+        self.set_tile_height(p_tile_size);
+        self.set_zoom_level(p_zoom);
         self.initialize_image_info();
-        log::info!(
-            "Found combinations {} with a file count of {}",
-            self,
-            t_file_count
-        );
     }
 
     pub fn calculate_file_count(&self) -> i32 {
@@ -80,46 +149,77 @@ impl<'a> ImageInfo<'a> {
     }
 
     pub fn _calculate_file_count(&self, p_zoom: i32, p_tile_width: i32, p_tile_height: i32) -> i32 {
-        let mut t_file_count = 0;
-
-        // println!("zoom: {} tile width {}", p_zoom, p_tile_width);
-        let mut reached_multiple_fullsized_tile = false;
-
-        for t_zoom in 0..p_zoom {
-            let t_zoom_factor = 2i32.pow(t_zoom as u32);
-            let t_width = self._image.get_width() / t_zoom_factor;
-            let t_height = self._image.get_height() / t_zoom_factor;
-
-            // Calculate number of tiles needed
-            let t_tile_x_count = (t_width as f64 / p_tile_width as f64).ceil() as i32;
-            let t_tile_y_count = (t_height as f64 / p_tile_height as f64).ceil() as i32;
-
-            // Each tile creates 4 files: 3 directories and 1 image
-            if t_width < p_tile_width && t_height < p_tile_height {
-                t_file_count += t_tile_x_count * t_tile_y_count * 3;
-                reached_multiple_fullsized_tile = true;
-            } else {
-                t_file_count += t_tile_x_count * t_tile_y_count * 4;
+        self.count_files_written(p_zoom, p_tile_width, p_tile_height)
+    }
+
+    // counts the files and directories written for one version, format, quality and rotation,
+    // info.json included, by working out every tile's path so edge tiles and regions shared
+    // between zoom levels are counted exactly.
+    fn count_files_written(&self, p_zoom: i32, p_tile_width: i32, p_tile_height: i32) -> i32 {
+        let mut t_regions = HashSet::new();
+        let mut t_tiles = HashSet::new();
+        for i in 0..=p_zoom {
+            let t_scale = 2i32.pow(i as u32);
+            let t_level_width = self._width / t_scale;
+            let t_level_height = self._height / t_scale;
+            let t_tiles_across = (t_level_width + p_tile_width - 1) / p_tile_width;
+            let t_tiles_down = (t_level_height + p_tile_height - 1) / p_tile_height;
+            for x in 0..t_tiles_across {
+                for y in 0..t_tiles_down {
+                    let t_x = x * p_tile_width * t_scale;
+                    let t_y = y * p_tile_height * t_scale;
+                    let t_width = (p_tile_width * t_scale).min(self._width - t_x);
+                    let t_height = (p_tile_height * t_scale).min(self._height - t_y);
+                    let t_region = (t_x, t_y, t_width, t_height);
+                    t_regions.insert(t_region);
+                    let t_scaled_width = (t_width as f64 / t_scale as f64).ceil() as i32;
+                    let t_scaled_height = (t_height as f64 / t_scale as f64).ceil() as i32;
+                    t_tiles.insert((t_region, t_scaled_width, t_scaled_height));
+                }
             }
         }
+        let t_sizes: HashSet<(i32, i32)> = (0..=p_zoom)
+            .map(|i| {
+                let t_scale = 2f64.powi(i);
+                (
+                    (self._width as f64 / t_scale).ceil() as i32,
+                    (self._height as f64 / t_scale).ceil() as i32,
+                )
+            })
+            // jpg is always written, so sizes too large for it are left out
+            .filter(|size| fits_formats(&[OutputFormat::Jpg], *size))
+            .collect();
+        let t_full_written = t_sizes.contains(&(self._width, self._height));
 
-        // If the tile is bigger than the image size we add 3 directories but
-        // for one we need to add 4.
-        if reached_multiple_fullsized_tile {
-            t_file_count += 1;
+        // the image's directory, then each region with a size, rotation and image under it
+        let mut t_file_count = 1 + t_regions.len() + t_tiles.len() * 3;
+        // the full directory, then each size and max with a rotation and image under them
+        if !t_sizes.is_empty() {
+            t_file_count += 1 + (t_sizes.len() + usize::from(t_full_written)) * 3;
         }
-
-        // println!("Total tiles {}", t_file_count);
-
-        // Add full sizes (1 full directory then three sub directories (size/rotation/file))
-        // And full w,h
-        // println!("Sizes {} should be 16", ((p_zoom + 2) * 3) + 4);
-        t_file_count += ((p_zoom + 2) * 3) + 4;
-
-        // Add info.json
+        // and the info.json
         t_file_count += 1;
+        t_file_count as i32
+    }
 
-        t_file_count
+    /// The number of pixels in the tiles and sizes written for one format and quality, e.g. to
+    /// estimate the space they'll take.
+    pub fn calculate_pixel_count(&self) -> u64 {
+        let t_tile_pixels: u64 = self
+            ._scale_factors
+            .iter()
+            .map(|&scale| {
+                let t_width = (self._width as f64 / scale as f64).ceil() as u64;
+                let t_height = (self._height as f64 / scale as f64).ceil() as u64;
+                t_width * t_height
+            })
+            .sum();
+        let t_size_pixels: u64 = self
+            ._sizes
+            .iter()
+            .map(|&(width, height)| width as u64 * height as u64)
+            .sum();
+        t_tile_pixels + t_size_pixels
     }
 
     fn initialize_image_info(&mut self) {
@@ -127,15 +227,15 @@ impl<'a> ImageInfo<'a> {
         self._sizes = Vec::new();
         for i in (0..=self._zoom_levels).rev() {
             let scale = 2i32.pow(i as u32);
-            let width = ((self._image.get_width() as f64) / (scale as f64)).ceil() as i32;
-            let height = ((self._image.get_height() as f64) / (scale as f64)).ceil() as i32;
+            let width = ((self._width as f64) / (scale as f64)).ceil() as i32;
+            let height = ((self._height as f64) / (scale as f64)).ceil() as i32;
             self._sizes.push((width, height));
             self._scale_factors.push(scale);
         }
     }
 
     pub fn id(&self) -> String {
-        self._image.id()
+        self._id.clone()
     }
 
     pub fn get_scale_factors(&self) -> Vec<i32> {
@@ -147,11 +247,11 @@ impl<'a> ImageInfo<'a> {
     }
 
     pub fn get_width(&self) -> i32 {
-        self._image.get_width()
+        self._width
     }
 
     pub fn get_height(&self) -> i32 {
-        self._image.get_height()
+        self._height
     }
 
     pub fn get_tile_width(&self) -> i32 {
@@ -170,12 +270,36 @@ impl<'a> ImageInfo<'a> {
         self._tile_height = p_tile_height;
     }
 
+    pub fn get_zoom_level(&self) -> i32 {
+        self._zoom_levels
+    }
+
     pub fn set_zoom_level(&mut self, p_zoom_level: i32) {
         self._zoom_levels = p_zoom_level;
     }
 
+    /// The decoded image.
+    ///
+    /// # Panics
+    ///
+    /// If this info was made with [`ImageInfo::from_dimensions`], which has no decoded image.
     pub fn get_image(&self) -> &IIIFImage {
         self._image
+            .expect("image info made from dimensions has no decoded image")
+    }
+
+    /// The decoded image's pixels.
+    ///
+    /// # Panics
+    ///
+    /// If this info was made with [`ImageInfo::from_dimensions`], which has no decoded image.
+    pub fn image(&self) -> &DynamicImage {
+        self.get_image().image()
+    }
+
+    /// Whether this info has a decoded image, i.e. wasn't made with [`ImageInfo::from_dimensions`].
+    pub fn has_image(&self) -> bool {
+        self._image.is_some()
     }
 }
 
@@ -0,0 +1,222 @@
+/**
+ * Typed IIIF Image API info.json documents, for writing the ones this crate generates and reading existing ones
+ */
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::error::TilerError;
+
+pub const CONTEXT_2: &str = "http://iiif.io/api/image/2/context.json";
+pub const CONTEXT_3: &str = "http://iiif.io/api/image/3/context.json";
+pub const PROTOCOL: &str = "http://iiif.io/api/image";
+pub const LEVEL0_2: &str = "http://iiif.io/api/image/2/level0.json";
+
+/// A property that may hold a single value or an array, kept as it was written
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        match self {
+            OneOrMany::One(value) => std::slice::from_ref(value).iter(),
+            OneOrMany::Many(values) => values.iter(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Size {
+    pub width: i32,
+    pub height: i32,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tile {
+    pub width: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<i32>,
+    pub scale_factors: Vec<i32>,
+}
+
+/// An entry in a v2 profile, either a compliance level URI or a description of extra features
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ProfileEntry {
+    Uri(String),
+    Description(ProfileDescription),
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct ProfileDescription {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub formats: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qualities: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supports: Option<Vec<String>>,
+    /// Any other keys, e.g. `maxWidth`
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// An IIIF Image API 2.1 info.json
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ImageService2 {
+    #[serde(rename = "@context")]
+    pub context: String,
+    #[serde(rename = "@id")]
+    pub id: String,
+    pub protocol: String,
+    pub width: i32,
+    pub height: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sizes: Option<Vec<Size>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tiles: Option<Vec<Tile>>,
+    pub profile: OneOrMany<ProfileEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attribution: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<OneOrMany<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logo: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<OneOrMany<Value>>,
+    /// Any other properties
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// An IIIF Image API 3.0 info.json
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageService3 {
+    #[serde(rename = "@context")]
+    pub context: OneOrMany<String>,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub service_type: String,
+    pub protocol: String,
+    pub profile: String,
+    pub width: i32,
+    pub height: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_width: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_height: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_area: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sizes: Option<Vec<Size>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tiles: Option<Vec<Tile>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_formats: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra_formats: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra_qualities: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra_features: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rights: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub part_of: Option<Vec<Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub see_also: Option<Vec<Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<Vec<Value>>,
+    /// Any other properties
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// An info.json of either version
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ImageService {
+    V3(ImageService3),
+    V2(ImageService2),
+}
+
+impl ImageService {
+    /// Parses an info.json, using its `@context` to tell the versions apart.
+    pub fn from_json(json: &str) -> Result<ImageService, TilerError> {
+        let value: Value = serde_json::from_str(json)?;
+        let is_v3 = match &value["@context"] {
+            Value::String(context) => context == CONTEXT_3,
+            Value::Array(contexts) => contexts.iter().any(|context| context == CONTEXT_3),
+            _ => false,
+        };
+        Ok(if is_v3 {
+            ImageService::V3(serde_json::from_value(value)?)
+        } else {
+            ImageService::V2(serde_json::from_value(value)?)
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    pub fn to_json_pretty(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+
+    pub fn id(&self) -> &str {
+        match self {
+            ImageService::V3(service) => &service.id,
+            ImageService::V2(service) => &service.id,
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        match self {
+            ImageService::V3(service) => service.width,
+            ImageService::V2(service) => service.width,
+        }
+    }
+
+    pub fn height(&self) -> i32 {
+        match self {
+            ImageService::V3(service) => service.height,
+            ImageService::V2(service) => service.height,
+        }
+    }
+
+    pub fn sizes(&self) -> &[Size] {
+        let sizes = match self {
+            ImageService::V3(service) => &service.sizes,
+            ImageService::V2(service) => &service.sizes,
+        };
+        sizes.as_deref().unwrap_or_default()
+    }
+
+    /// The format full images and sizes are requested in: the first preferred format if any,
+    /// otherwise `jpg`.
+    pub fn preferred_format(&self) -> &str {
+        let formats = match self {
+            ImageService::V3(service) => service.preferred_formats.as_deref(),
+            ImageService::V2(service) => service.profile.iter().find_map(|entry| match entry {
+                ProfileEntry::Description(description) => description.formats.as_deref(),
+                ProfileEntry::Uri(_) => None,
+            }),
+        };
+        formats
+            .and_then(|formats| formats.first())
+            .map_or("jpg", String::as_str)
+    }
+
+    pub fn tiles(&self) -> &[Tile] {
+        let tiles = match self {
+            ImageService::V3(service) => &service.tiles,
+            ImageService::V2(service) => &service.tiles,
+        };
+        tiles.as_deref().unwrap_or_default()
+    }
+}
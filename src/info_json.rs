@@ -1,22 +1,101 @@
 /**
  * This class generates the IIIF info.json for an image
  */
-use serde_json::{json, Map, Value};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
+use crate::error::TilerError;
 use crate::image_info::ImageInfo;
+use crate::image_service::{
+    ImageService, ImageService2, ImageService3, OneOrMany, ProfileDescription, ProfileEntry, Size,
+    Tile, CONTEXT_2, CONTEXT_3, LEVEL0_2, PROTOCOL,
+};
+use crate::output::{fits_formats, level0_formats, OutputFormat, Quality, Rotation};
 
-#[derive(Debug, PartialEq, Default)]
+// everything but the characters RFC 3986 leaves unreserved is encoded in identifiers
+const ID_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Percent-encodes an identifier for use in a URI. The Image API needs `/` encoded too, so
+/// `book1/page001` is `book1%2Fpage001`, while its files are written under `book1/page001/` where
+/// hosts that decode the path before looking up the file find them.
+pub fn encode_id(id: &str) -> String {
+    utf8_percent_encode(id, ID_ENCODE_SET).to_string()
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
 pub enum IIIFVersion {
     VERSION3,
     #[default]
     VERSION211,
 }
 
+impl IIIFVersion {
+    /// The directory each version's service goes in when several versions are written at once
+    pub fn dir_name(&self) -> &'static str {
+        match self {
+            IIIFVersion::VERSION3 => "v3",
+            IIIFVersion::VERSION211 => "v2",
+        }
+    }
+}
+
+/// Descriptive properties added to the info.json. Each is only written for the versions that define it.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Properties {
+    /// Rights statement or licence URI. v2 writes it as the `license` when none is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rights: Option<String>,
+    /// Resources the image is part of, e.g. its manifest. v3 only.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub part_of: Vec<Value>,
+    /// Machine readable descriptions of the image. v3 only.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub see_also: Vec<Value>,
+    /// Nested services such as auth or physical dimensions.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub service: Vec<Value>,
+    /// v2 only.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub license: Vec<String>,
+    /// v2 only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attribution: Option<Value>,
+    /// v2 only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logo: Option<Value>,
+    /// Any other properties, written as they are for both versions.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Properties {
+    pub fn from_json(json: &str) -> Result<Properties, TilerError> {
+        serde_json::from_str(json)
+            .map_err(|e| TilerError::InvalidParameter(format!("Invalid properties: {}", e)))
+    }
+
+    pub fn from_toml(toml: &str) -> Result<Properties, TilerError> {
+        toml::from_str(toml)
+            .map_err(|e| TilerError::InvalidParameter(format!("Invalid properties: {}", e)))
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct InfoJSON<'a> {
     image_info: &'a ImageInfo<'a>,
     uri: String,
     version: &'a IIIFVersion,
+    sizes: Option<Vec<(i32, i32)>>,
+    formats: Vec<OutputFormat>,
+    qualities: Vec<Quality>,
+    rotations: Vec<Rotation>,
+    properties: Properties,
 }
 
 impl<'a> InfoJSON<'a> {
@@ -25,11 +104,87 @@ impl<'a> InfoJSON<'a> {
             image_info,
             uri: uri.to_string(),
             version,
+            sizes: None,
+            formats: vec![OutputFormat::Jpg],
+            qualities: Vec::new(),
+            rotations: Vec::new(),
+            properties: Properties::default(),
         }
     }
 
+    /// Sets the formats the tiles were written in, with the preferred format first. Level 0's jpg
+    /// is listed after them when they don't include it.
+    pub fn set_formats(&mut self, formats: Vec<OutputFormat>) {
+        self.formats = level0_formats(formats);
+    }
+
+    // formats beyond the jpg that level 0 already promises
+    fn extra_formats(&self) -> Vec<String> {
+        self.formats
+            .iter()
+            .filter(|format| **format != OutputFormat::Jpg)
+            .map(|format| format.extension().to_owned())
+            .collect()
+    }
+
+    fn all_formats(&self) -> Vec<String> {
+        self.formats
+            .iter()
+            .map(|format| format.extension().to_owned())
+            .collect()
+    }
+
+    /// Sets the qualities that were written alongside `default`.
+    pub fn set_qualities(&mut self, qualities: Vec<Quality>) {
+        self.qualities = qualities;
+    }
+
+    fn extra_qualities(&self) -> Vec<String> {
+        self.qualities
+            .iter()
+            .map(|quality| quality.name().to_owned())
+            .collect()
+    }
+
+    /// Sets the rotations that were written alongside `0` for every region, tiles included.
+    pub fn set_rotations(&mut self, rotations: Vec<Rotation>) {
+        self.rotations = rotations;
+    }
+
+    // rotationBy90s and mirroring, for whichever rotations were written
+    fn extra_features(&self) -> Vec<String> {
+        let mut features = Vec::new();
+        if self
+            .rotations
+            .iter()
+            .any(|rotation| *rotation != Rotation::Mirror)
+        {
+            features.push("rotationBy90s".to_owned());
+        }
+        if self.rotations.contains(&Rotation::Mirror) {
+            features.push("mirroring".to_owned());
+        }
+        features
+    }
+
+    /// Sets the descriptive properties, replacing any set before.
+    pub fn set_properties(&mut self, properties: Properties) {
+        self.properties = properties;
+    }
+
+    /// Adds a property that isn't covered by [`Properties`], e.g. a local extension.
+    pub fn set_property(&mut self, name: &str, value: Value) {
+        self.properties.extra.insert(name.to_owned(), value);
+    }
+
+    /// Lists only these sizes rather than every size in the image info, e.g. when some weren't written.
+    pub fn set_sizes(&mut self, sizes: Vec<(i32, i32)>) {
+        self.sizes = Some(sizes);
+    }
+
+    /// The image's URI, with its identifier percent-encoded
     pub fn id(&self) -> String {
-        format!("{}{}", self.uri, self.image_info.id())
+        format!("{}{}", self.uri, encode_id(&self.image_info.id()))
     }
 
     pub fn width(&self) -> i32 {
@@ -40,59 +195,111 @@ impl<'a> InfoJSON<'a> {
         self.image_info.get_height()
     }
 
-    pub fn to_json(&self) -> Result<String, serde_json::Error> {
-        let mut info_json = Map::new();
+    /// Builds the info.json for this version
+    pub fn to_service(&self) -> ImageService {
+        let sizes = match &self.sizes {
+            Some(sizes) => sizes.clone(),
+            // sizes too large for any of the formats aren't written
+            None => self
+                .image_info
+                .get_sizes()
+                .into_iter()
+                .filter(|size| fits_formats(&self.formats, *size))
+                .collect(),
+        };
+        let sizes = Some(
+            sizes
+                .iter()
+                .map(|&(width, height)| Size { width, height })
+                .collect(),
+        );
+        let tiles = Some(vec![Tile {
+            width: self.image_info.get_tile_width(),
+            height: Some(self.image_info.get_tile_height()),
+            scale_factors: self.image_info.get_scale_factors(),
+        }]);
+        let non_empty = |values: Vec<String>| Some(values).filter(|values| !values.is_empty());
+        let properties = &self.properties;
+        let non_empty_values =
+            |values: &Vec<Value>| Some(values.clone()).filter(|values| !values.is_empty());
 
         match self.version {
             IIIFVersion::VERSION3 => {
-                info_json.insert(
-                    "@context".to_owned(),
-                    Value::String("http://iiif.io/api/image/3/context.json".to_owned()),
-                );
-                info_json.insert("id".to_owned(), Value::String(self.id()));
-                info_json.insert(
-                    "type".to_owned(),
-                    Value::String("ImageService3".to_string()),
-                );
-                info_json.insert("profile".to_owned(), Value::String("level0".to_string()));
+                // Advertise any formats other than the default jpg
+                let (preferred_formats, extra_formats) = if self.formats == [OutputFormat::Jpg] {
+                    (None, None)
+                } else {
+                    (Some(self.all_formats()), non_empty(self.extra_formats()))
+                };
+                ImageService::V3(ImageService3 {
+                    context: OneOrMany::One(CONTEXT_3.to_owned()),
+                    id: self.id(),
+                    service_type: "ImageService3".to_owned(),
+                    protocol: PROTOCOL.to_owned(),
+                    profile: "level0".to_owned(),
+                    width: self.width(),
+                    height: self.height(),
+                    max_width: None,
+                    max_height: None,
+                    max_area: None,
+                    sizes,
+                    tiles,
+                    preferred_formats,
+                    extra_formats,
+                    extra_qualities: non_empty(self.extra_qualities()),
+                    extra_features: non_empty(self.extra_features()),
+                    rights: properties.rights.clone(),
+                    part_of: non_empty_values(&properties.part_of),
+                    see_also: non_empty_values(&properties.see_also),
+                    service: non_empty_values(&properties.service),
+                    extra: properties.extra.clone(),
+                })
             }
             IIIFVersion::VERSION211 => {
-                info_json.insert(
-                    "@context".to_owned(),
-                    Value::String("http://iiif.io/api/image/2/context.json".to_owned()),
-                );
-                info_json.insert("id".to_owned(), Value::String(self.id()));
-                info_json.insert(
-                    "profile".to_owned(),
-                    Value::String("http://iiif.io/api/image/2/level0.json".to_owned()),
-                );
+                // level 0 plus a profile description of everything that was actually written
+                let mut qualities = vec!["default".to_owned()];
+                qualities.extend(self.extra_qualities());
+                let description = ProfileDescription {
+                    formats: Some(self.all_formats()),
+                    qualities: Some(qualities),
+                    supports: Some(self.extra_features()),
+                    extra: Map::new(),
+                };
+                let license = match (properties.license.as_slice(), &properties.rights) {
+                    ([], None) => None,
+                    ([], Some(rights)) => Some(OneOrMany::One(rights.clone())),
+                    ([license], _) => Some(OneOrMany::One(license.clone())),
+                    (licenses, _) => Some(OneOrMany::Many(licenses.to_vec())),
+                };
+                ImageService::V2(ImageService2 {
+                    context: CONTEXT_2.to_owned(),
+                    id: self.id(),
+                    protocol: PROTOCOL.to_owned(),
+                    width: self.width(),
+                    height: self.height(),
+                    sizes,
+                    tiles,
+                    profile: OneOrMany::Many(vec![
+                        ProfileEntry::Uri(LEVEL0_2.to_owned()),
+                        ProfileEntry::Description(description),
+                    ]),
+                    attribution: properties.attribution.clone(),
+                    license,
+                    logo: properties.logo.clone(),
+                    service: non_empty_values(&properties.service).map(OneOrMany::Many),
+                    extra: properties.extra.clone(),
+                })
             }
         }
+    }
 
-        info_json.insert(
-            "protocol".to_owned(),
-            Value::String("http://iiif.io/api/image".to_owned()),
-        );
-        info_json.insert("width".to_owned(), Value::Number(self.width().into()));
-        info_json.insert("height".to_owned(), Value::Number(self.height().into()));
+    /// The info.json on a single line
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        self.to_service().to_json()
+    }
 
-        // Add sizes
-        let sizes_json: Vec<Value> = self
-            .image_info
-            .get_sizes()
-            .iter()
-            .map(|&(x, y)| json!({ "height": y, "width": x }))
-            .collect();
-        info_json.insert("sizes".to_owned(), Value::Array(sizes_json));
-
-        // Add tiles
-        let tiles_json = vec![json!({
-            "width": self.image_info.get_tile_width(),
-            "height": self.image_info.get_tile_height(),
-            "scaleFactors": self.image_info.get_scale_factors()
-        })];
-        info_json.insert("tiles".to_owned(), Value::Array(tiles_json));
-
-        serde_json::to_string(&info_json)
+    /// The info.json indented for reading
+    pub fn to_json_pretty(&self) -> Result<String, serde_json::Error> {
+        self.to_service().to_json_pretty()
     }
 }
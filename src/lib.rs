@@ -1,5 +1,10 @@
 //declare modules so tests can use them
+pub mod error;
 pub mod iiif_image;
 pub mod image_info;
+pub mod image_service;
 pub mod info_json;
+pub mod output;
+pub mod presentation;
+pub mod streaming;
 pub mod tiler;
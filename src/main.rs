@@ -1,27 +1,74 @@
-use std::{fs::read_dir, fs::File, path::Path};
+use std::{
+    collections::HashMap,
+    fs::File,
+    fs::{create_dir_all, read_dir, read_to_string},
+    io::Write,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use glob::Pattern;
+use image::{imageops::FilterType, metadata::Orientation, ImageFormat};
 extern crate image;
+pub mod error;
 pub mod info_json;
-use info_json::IIIFVersion;
+use info_json::{encode_id, IIIFVersion, Properties};
 pub mod image_info;
+pub mod image_service;
 use image_info::ImageInfo;
+use image_service::ImageService;
 pub mod iiif_image;
-use iiif_image::IIIFImage;
+use iiif_image::{
+    detect_format, id_from_path, page_id, read_dimensions, read_icc_profile, read_orientation,
+    read_page_dimensions, IIIFImage, IccMode, IdentifierStrategy, LoadOptions, OrientationMode,
+    PixelMode, ToneMapping,
+};
+pub mod output;
+pub mod presentation;
+use output::{
+    level0_formats, Bitonal, ChromaSubsampling, EncoderSettings, OutputFormat, PngCompression,
+    Quality, Rotation,
+};
+use presentation::{CollectionBuilder, ManifestBuilder};
+pub mod streaming;
+use streaming::can_stream;
 pub mod tiler;
-use anyhow::{Error, Result};
-use log::info;
-use serde_json::{to_writer_pretty, Value};
-use tiler::Tiler;
+use anyhow::{Context, Error, Result};
+use log::{error, info};
+use tiler::{Tiler, TilingMode};
 
 const DEFAULT_URI: &str = "http://localhost:8887/iiif/";
 const DEFAULT_VERSION: &str = "3";
 const DEFAULT_ZOOM_LEVELS: i32 = 5;
 const DEFAULT_TILE_SIZE: i32 = 1024;
 const DEFAULT_OUTPUT_DIR: &str = "iiif";
+const DEFAULT_MEMORY_BUDGET_MB: usize = 1024;
 
-#[derive(Parser, Default, Debug)]
-#[command(author = "Ryan Muther", version, about = "IIIF Image Tiler")]
+#[derive(Parser, Debug)]
+#[command(
+    author = "Ryan Muther",
+    version,
+    about = "IIIF Image Tiler",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Option<Arguments>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the files, pixels and approximate bytes tiling would write, without writing anything.
+    /// Takes the same options.
+    Estimate(Arguments),
+}
+
+#[derive(Args, Default, Debug)]
 struct Arguments {
     /// The file or directory path to the image(s) to be processed
     path: String,
@@ -30,96 +77,1020 @@ struct Arguments {
     #[arg(short, long, default_value = DEFAULT_URI)]
     uri: String,
 
-    /// Set the IIIF version, options are `2` or `3`.
+    /// Set the IIIF version, options are `2` or `3`. `2,3` writes both, each under its own `v2` or `v3` directory.
     #[arg(short, long, default_value = DEFAULT_VERSION)]
     iiif_version: String,
 
     /// Set the number of zoom levels for this image.
-    #[arg(short, long, default_value_t = DEFAULT_ZOOM_LEVELS)]
+    #[arg(short, long, default_value_t = DEFAULT_ZOOM_LEVELS, value_parser = clap::value_parser!(i32).range(0..))]
     zoom_levels: i32,
 
     /// Set the tile size.
-    #[arg(short, long, default_value_t = DEFAULT_TILE_SIZE)]
+    #[arg(short, long, default_value_t = DEFAULT_TILE_SIZE, value_parser = clap::value_parser!(i32).range(1..))]
     tile_size: i32,
 
+    /// Pick the tile size and zoom levels so each image is written in fewer than N files,
+    /// counting every IIIF version, format, quality and rotation, e.g. for hosts that limit the
+    /// number of files. Overrides --tile-size and --zoom-levels.
+    #[arg(long, value_name = "N")]
+    max_files: Option<i32>,
+
     /// Directory where the image tiles are stored.
     #[arg(short, long, default_value = DEFAULT_OUTPUT_DIR)]
     output_dir: String,
 
+    /// Output format for tiles and sizes: jpg, png, webp, tif or gif. Repeat to write several
+    /// formats, the first is the preferred one. jpg is always written too, as IIIF level 0 needs it.
+    #[arg(short, long = "format", value_name = "FORMAT")]
+    formats: Vec<OutputFormat>,
+
+    /// Also write this quality next to `default`: gray or bitonal. Repeat for both.
+    #[arg(long = "quality", value_name = "QUALITY")]
+    qualities: Vec<Quality>,
+
+    /// Brightness from 0 to 255 at or above which bitonal pixels become white.
+    #[arg(long, default_value_t = 128, conflicts_with = "dither")]
+    bitonal_threshold: u8,
+
+    /// Dither the bitonal quality instead of thresholding it.
+    #[arg(long)]
+    dither: bool,
+
+    /// Also write the full image and sizes rotated: 90, 180, 270 or !0 (mirrored). Repeat for several.
+    #[arg(long = "rotation", value_name = "ROTATION")]
+    rotations: Vec<Rotation>,
+
+    /// Write the rotations for tiles too, and advertise them in the info.json.
+    #[arg(long)]
+    rotate_tiles: bool,
+
+    /// JPEG quality from 1 to 100.
+    #[arg(long, default_value_t = 75, value_parser = clap::value_parser!(u8).range(1..=100))]
+    jpeg_quality: u8,
+
+    /// Write progressive JPEGs.
+    #[arg(long)]
+    progressive: bool,
+
+    /// JPEG chroma subsampling: 444, 422 or 420.
+    #[arg(long, default_value = "420")]
+    chroma_subsampling: ChromaSubsampling,
+
+    /// PNG compression: fast, default or best.
+    #[arg(long, default_value = "default")]
+    png_compression: PngCompression,
+
+    /// Write lossy WebP at this quality from 0 to 100 instead of lossless WebP. Needs the lossy-webp feature.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+    webp_quality: Option<u8>,
+
+    /// Keep the source's alpha channel and 16-bit depth instead of converting to 8-bit RGB.
+    /// Images are decoded in memory even with --streaming.
+    #[arg(long)]
+    preserve_pixels: bool,
+
+    /// Keep the pixels as they're stored instead of turning them upright using the EXIF orientation.
+    #[arg(long)]
+    ignore_orientation: bool,
+
+    /// What's done with the source's ICC colour profile: dropped (ignore), used to convert the
+    /// pixels to sRGB (convert), or embedded in every JPEG, PNG and WebP (embed).
+    #[arg(long, value_enum, default_value_t = IccHandling::Ignore)]
+    icc_profile: IccHandling,
+
+    /// How HDR and EXR images are brought into range: exposed for their average brightness with
+    /// the highlights compressed (reinhard), or clipped above 1 (clip).
+    #[arg(long, value_enum, default_value_t = ToneMap::Reinhard)]
+    tone_mapping: ToneMap,
+
+    /// Tile each page of a multi-page TIFF and each frame of an animated GIF as its own image,
+    /// identified as `<name>_p0001`, `<name>_p0002` and so on. They're decoded in memory even with
+    /// --streaming, and with --manifest get a manifest of their own in `<name>/`.
+    #[arg(long)]
+    pages: bool,
+
+    /// Hex RGB colour transparent pixels are flattened onto when writing JPEGs.
+    #[arg(long, default_value = "ffffff", value_parser = parse_background)]
+    background: [u8; 3],
+
+    /// Build each zoom level from the level above it instead of from the full resolution image.
+    #[arg(long)]
+    pyramid: bool,
+
+    /// Resampling filter used to downsample pyramid levels.
+    #[arg(long, value_enum, default_value_t = ResampleFilter::Triangle)]
+    filter: ResampleFilter,
+
+    /// Tile PNG and TIFF images a band of rows at a time instead of decoding them into memory.
+    #[arg(long)]
+    streaming: bool,
+
+    /// Memory budget in megabytes when streaming. Sizes that don't fit in it, the full sized image included, aren't written.
+    #[arg(long, default_value_t = DEFAULT_MEMORY_BUDGET_MB)]
+    memory_budget: usize,
+
+    /// Number of threads used to encode tiles. `0` uses one per CPU core.
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+
+    /// Stop tiling an image at the first tile that fails to write.
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Rights statement or licence URI for the info.json. A `rights` in an image's sidecar
+    /// `<name>.json` or `<name>.toml` file takes precedence.
+    #[arg(long)]
+    rights: Option<String>,
+
+    /// Also write a Presentation manifest: one canvas for an image, or one canvas per image in
+    /// filename order for a directory. Version 3 images get a Presentation 3 manifest and version 2
+    /// images a Presentation 2.1 one.
+    #[arg(long)]
+    manifest: bool,
+
+    /// Also process images in sub-directories. Their identifiers include the folders they're in,
+    /// e.g. `book1/page001`, and with --manifest each folder gets a manifest and folders with
+    /// sub-folders a collection.
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Only process files matching this glob, e.g. `*.tif` or `book1/*`. Repeat for several.
+    #[arg(long = "include", value_name = "GLOB", value_parser = parse_pattern)]
+    includes: Vec<Pattern>,
+
+    /// Skip files and directories matching this glob. Repeat for several.
+    #[arg(long = "exclude", value_name = "GLOB", value_parser = parse_pattern)]
+    excludes: Vec<Pattern>,
+
+    /// How image identifiers are worked out: from the file name without its extension (stem), the
+    /// path relative to the directory (path, e.g. `book1/page001`) or the file's contents (hash).
+    #[arg(long, value_enum, default_value_t = IdentifierKind::Path)]
+    identifier: IdentifierKind,
+
+    /// Joins the folders in `path` identifiers, e.g. `_` for `book1_page001`.
+    #[arg(long, default_value = "/")]
+    id_separator: String,
+
+    /// Identifier template, e.g. `{parent}_{stem}`. The placeholders are {stem}, {parent}, {ext}
+    /// and {hash}.
+    #[arg(long, conflicts_with_all = ["identifier", "id_map"])]
+    id_template: Option<String>,
+
+    /// CSV of file paths, relative to the directory being processed, and their identifiers.
+    #[arg(long, value_name = "CSV", conflicts_with = "identifier")]
+    id_map: Option<PathBuf>,
+
+    /// Write the info.json on a single line instead of indenting it.
+    #[arg(long)]
+    compact: bool,
+
     /// Enable verbose logging
     #[arg(short, long)]
     verbose: bool,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+enum ResampleFilter {
+    Nearest,
+    #[default]
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum IccHandling {
+    #[default]
+    Ignore,
+    Convert,
+    Embed,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+enum ToneMap {
+    #[default]
+    Reinhard,
+    Clip,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+enum IdentifierKind {
+    Stem,
+    #[default]
+    Path,
+    Hash,
+}
+
+impl From<ResampleFilter> for FilterType {
+    fn from(filter: ResampleFilter) -> Self {
+        match filter {
+            ResampleFilter::Nearest => FilterType::Nearest,
+            ResampleFilter::Triangle => FilterType::Triangle,
+            ResampleFilter::CatmullRom => FilterType::CatmullRom,
+            ResampleFilter::Gaussian => FilterType::Gaussian,
+            ResampleFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+impl From<ToneMap> for ToneMapping {
+    fn from(tone_map: ToneMap) -> Self {
+        match tone_map {
+            ToneMap::Reinhard => ToneMapping::Reinhard,
+            ToneMap::Clip => ToneMapping::Clip,
+        }
+    }
+}
+
+// Parses a hex colour such as `ffffff` or `#ffffff`
+fn parse_background(value: &str) -> Result<[u8; 3], String> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    let channel = |i: usize| {
+        hex.get(i..i + 2)
+            .and_then(|c| u8::from_str_radix(c, 16).ok())
+    };
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok([r, g, b]),
+        _ => Err(format!("'{}' isn't a hex colour like ffffff", value)),
+    }
+}
+
+fn parse_pattern(value: &str) -> Result<Pattern, String> {
+    Pattern::new(value).map_err(|e| format!("'{}' isn't a valid glob: {}", value, e))
+}
+
+// an image's identifier and each version's info.json
+type TiledImage = (String, Vec<(IIIFVersion, String)>);
+
+// What a directory run shares between its folders
+struct DirectoryRun {
+    identifier: IdentifierStrategy,
+    // each identifier given out so far and the image it was given to
+    ids: HashMap<String, PathBuf>,
+    failures: Vec<(PathBuf, Error)>,
+}
+
+// Processes every image in the directory in filename order, and in its sub-directories when
+// recursive, returning the images that failed along with why
 fn process_directory(
     args: &Arguments,
     dir_path: &str,
-    iiif_version: &IIIFVersion,
-) -> Result<(), Error> {
+    iiif_versions: &[IIIFVersion],
+) -> Result<Vec<(PathBuf, Error)>, Error> {
     let dir_path = Path::new(dir_path);
+    let mut run = DirectoryRun {
+        identifier: identifier_strategy(args, dir_path)?,
+        ids: HashMap::new(),
+        failures: Vec::new(),
+    };
+    let label = folder_label(dir_path)?;
+    process_folder(
+        args,
+        iiif_versions,
+        &mut run,
+        dir_path,
+        Path::new(""),
+        &label,
+    )?;
+    Ok(run.failures)
+}
+
+// Processes the images in `root/relative`, then its sub-directories if recursive. Returns each
+// version's manifest of the folder's images and, if it has any, collection of its sub-folders.
+fn process_folder(
+    args: &Arguments,
+    iiif_versions: &[IIIFVersion],
+    run: &mut DirectoryRun,
+    root: &Path,
+    relative: &Path,
+    label: &str,
+) -> Result<Vec<(ManifestBuilder, Option<CollectionBuilder>)>, Error> {
+    let mut builders: Vec<(ManifestBuilder, CollectionBuilder)> = iiif_versions
+        .iter()
+        .map(|version| {
+            let (_, uri) = version_root(args, iiif_versions, version);
+            let base = format!("{}{}", uri, folder_uri(relative));
+            (
+                ManifestBuilder::new(&base, label),
+                CollectionBuilder::new(&base, label),
+            )
+        })
+        .collect();
 
     // Read the directory
-    let entries = read_dir(dir_path)?;
+    let mut paths = Vec::new();
+    for entry in read_dir(root.join(relative))? {
+        paths.push(entry?.path()); // Handle `Result<DirEntry, Error>`
+    }
+    paths.sort();
 
-    for entry in entries {
-        let entry = entry?; // Handle `Result<DirEntry, Error>`
-        let path = entry.path();
+    let mut sub_folders = Vec::new();
+    // each multi-page file's identifier and manifests
+    let mut documents = Vec::new();
+    for path in paths {
+        let relative_path = relative.join(path.file_name().unwrap_or_default());
+        if path.is_dir() {
+            if args.recursive && !matches_any(&args.excludes, &relative_path) {
+                sub_folders.push((path, relative_path));
+            }
+            continue;
+        }
+        // Process only the image files the filters allow
+        if !is_wanted(args, &path, &relative_path) {
+            continue;
+        }
+        let result = match run.identifier.identify(&path) {
+            Ok(id) => claim_id(&mut run.ids, &id, &path).and_then(|()| {
+                if args.pages && has_pages(&path) {
+                    process_pages(args, &path, &id, iiif_versions, &mut run.ids)
+                } else {
+                    process_image(args, &path, &id, iiif_versions)
+                        .map(|manifests| vec![(id.clone(), manifests)])
+                }
+                .map(|pages| (id, pages))
+            }),
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok((id, pages)) if pages.len() > 1 => {
+                let label = id_from_path(&path)?;
+                documents.push((
+                    id.clone(),
+                    page_manifests(args, iiif_versions, &id, &label, &pages)?,
+                ));
+            }
+            Ok((_, pages)) => add_canvases(&mut builders, &path, &pages[0].1)?,
+            Err(e) => {
+                error!("Failed to process {}: {:#}", path.display(), e);
+                run.failures.push((path, e));
+            }
+        }
+    }
 
-        // Process only files with valid extensions
-        if path.is_file() && is_image_file(&path) {
-            // Use the path as a string safely
-            if let Some(path_str) = path.to_str() {
-                process_image(args, path_str, iiif_version)?;
-            } else {
-                return Err(Error::msg(format!(
-                    "Invalid UTF-8 in file path: {:?}",
-                    path
-                )));
+    // the folder's own manifest comes before its multi-page files and sub-folders in its collection
+    for (manifest, collection) in &mut builders {
+        if !manifest.is_empty() {
+            collection.add_manifest(manifest);
+        }
+    }
+    for (_, manifests) in &documents {
+        for ((_, collection), manifest) in builders.iter_mut().zip(manifests) {
+            collection.add_manifest(manifest);
+        }
+    }
+    let mut has_members = !documents.is_empty();
+    for (path, relative_path) in sub_folders {
+        let sub_label = folder_label(&path)?;
+        let sub_builders =
+            process_folder(args, iiif_versions, run, root, &relative_path, &sub_label)?;
+        for ((_, collection), (sub_manifest, sub_collection)) in
+            builders.iter_mut().zip(&sub_builders)
+        {
+            // a folder with sub-folders is linked by its collection, otherwise by its manifest
+            if let Some(sub_collection) = sub_collection {
+                collection.add_collection(sub_collection);
+                has_members = true;
+            } else if !sub_manifest.is_empty() {
+                collection.add_manifest(sub_manifest);
+                has_members = true;
+            }
+        }
+    }
+    if args.manifest {
+        for (version, (manifest, collection)) in iiif_versions.iter().zip(&builders) {
+            let (dir, _) = version_root(args, iiif_versions, version);
+            let dir = dir.join(relative);
+            write_presentation(args, version, manifest, &dir)?;
+            if has_members {
+                write_collection(args, version, collection, &dir)?;
+            }
+        }
+        for (id, manifests) in &documents {
+            for (version, manifest) in iiif_versions.iter().zip(manifests) {
+                let (dir, _) = version_root(args, iiif_versions, version);
+                write_presentation(args, version, manifest, &dir.join(id))?;
             }
         }
     }
+    // the collection is only needed to link multi-page files and sub-folders
+    Ok(builders
+        .into_iter()
+        .map(|(manifest, collection)| (manifest, has_members.then_some(collection)))
+        .collect())
+}
+
+// Works out the identifier strategy for images under `root`. Relative paths in an --id-map
+// CSV are relative to `root`.
+fn identifier_strategy(args: &Arguments, root: &Path) -> Result<IdentifierStrategy, Error> {
+    if let Some(template) = &args.id_template {
+        return Ok(IdentifierStrategy::Template(template.clone()));
+    }
+    if let Some(csv_path) = &args.id_map {
+        let IdentifierStrategy::Mapping(mapping) = IdentifierStrategy::from_csv(csv_path)? else {
+            unreachable!("from_csv returns a mapping");
+        };
+        let mapping = mapping
+            .into_iter()
+            .map(|(file, id)| (root.join(file), id))
+            .collect();
+        return Ok(IdentifierStrategy::Mapping(mapping));
+    }
+    Ok(match args.identifier {
+        IdentifierKind::Stem => IdentifierStrategy::FileStem,
+        IdentifierKind::Path => IdentifierStrategy::RelativePath {
+            root: root.to_path_buf(),
+            separator: args.id_separator.clone(),
+        },
+        IdentifierKind::Hash => IdentifierStrategy::ContentHash,
+    })
+}
+
+// the folder's name, used to label its manifest and collection
+fn folder_label(dir_path: &Path) -> Result<String, Error> {
+    Ok(dir_path.canonicalize()?.file_name().map_or_else(
+        || "images".to_owned(),
+        |name| name.to_string_lossy().into_owned(),
+    ))
+}
+
+// the folder's path in URIs, ending in `/` unless it's the top folder
+fn folder_uri(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|component| format!("{}/", encode_id(&component.as_os_str().to_string_lossy())))
+        .collect()
+}
+
+// whether the path is an image file --include and --exclude let through
+fn is_wanted(args: &Arguments, path: &Path, relative_path: &Path) -> bool {
+    path.is_file()
+        && !matches_any(&args.excludes, relative_path)
+        && (args.includes.is_empty() || matches_any(&args.includes, relative_path))
+        && is_image_file(path)
+}
+
+// finds the images a directory run would process, in the same order
+fn find_images(
+    args: &Arguments,
+    root: &Path,
+    relative: &Path,
+    images: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    let mut paths = Vec::new();
+    for entry in read_dir(root.join(relative))? {
+        paths.push(entry?.path());
+    }
+    paths.sort();
+
+    let mut sub_folders = Vec::new();
+    for path in paths {
+        let relative_path = relative.join(path.file_name().unwrap_or_default());
+        if path.is_dir() {
+            if args.recursive && !matches_any(&args.excludes, &relative_path) {
+                sub_folders.push(relative_path);
+            }
+        } else if is_wanted(args, &path, &relative_path) {
+            images.push(path);
+        }
+    }
+    for relative_path in sub_folders {
+        find_images(args, root, &relative_path, images)?;
+    }
     Ok(())
 }
 
-fn is_image_file(path: &Path) -> bool {
-    match path.extension().and_then(|ext| ext.to_str()) {
-        Some(ext) => matches!(
-            ext.to_lowercase().as_str(),
-            "jpg" | "jpeg" | "png" | "bmp" | "tiff"
-        ),
-        None => false,
+// whether any pattern matches the path relative to the top folder or just its name
+fn matches_any(patterns: &[Pattern], relative_path: &Path) -> bool {
+    let name = relative_path.file_name().unwrap_or_default();
+    patterns
+        .iter()
+        .any(|pattern| pattern.matches_path(relative_path) || pattern.matches_path(Path::new(name)))
+}
+
+// adds the image as a canvas to each version's manifest
+fn add_canvases(
+    builders: &mut [(ManifestBuilder, CollectionBuilder)],
+    img_path: &Path,
+    manifests: &[(IIIFVersion, String)],
+) -> Result<(), Error> {
+    let label = id_from_path(img_path)?;
+    for ((builder, _), (_, manifest)) in builders.iter_mut().zip(manifests) {
+        builder.add_canvas(&label, ImageService::from_json(manifest)?);
     }
+    Ok(())
 }
 
-fn process_image(
+// the directory and URI a version's images are written under
+fn version_root(
+    args: &Arguments,
+    iiif_versions: &[IIIFVersion],
+    version: &IIIFVersion,
+) -> (PathBuf, String) {
+    if iiif_versions.len() > 1 {
+        (
+            Path::new(&args.output_dir).join(version.dir_name()),
+            format!("{}{}/", args.uri, version.dir_name()),
+        )
+    } else {
+        (PathBuf::from(&args.output_dir), args.uri.clone())
+    }
+}
+
+// writes the collection.json linking the folder's manifest and sub-folders
+fn write_collection(
+    args: &Arguments,
+    version: &IIIFVersion,
+    builder: &CollectionBuilder,
+    dir: &Path,
+) -> Result<(), Error> {
+    let json = match (version, args.compact) {
+        (IIIFVersion::VERSION3, true) => builder.to_collection3().to_json()?,
+        (IIIFVersion::VERSION3, false) => builder.to_collection3().to_json_pretty()?,
+        (IIIFVersion::VERSION211, true) => builder.to_collection2().to_json()?,
+        (IIIFVersion::VERSION211, false) => builder.to_collection2().to_json_pretty()?,
+    };
+    // the folder has no images of its own on disk when identifiers don't mirror it
+    create_dir_all(dir)?;
+    let file_path = dir.join("collection.json");
+    info!("Writing collection: {}", file_path.display());
+    let mut file = File::create(file_path)?;
+    file.write_all(json.as_bytes())?;
+    Ok(())
+}
+
+// writes the manifest.json, Presentation 3 for v3 images and Presentation 2.1 for v2 images
+fn write_presentation(
     args: &Arguments,
-    img_path: &str,
-    iiif_version: &IIIFVersion,
+    version: &IIIFVersion,
+    builder: &ManifestBuilder,
+    dir: &Path,
 ) -> Result<(), Error> {
-    info!("Loading image from: {}", img_path);
-    let img = IIIFImage::new(img_path);
+    if builder.is_empty() {
+        return Ok(());
+    }
+    let json = match (version, args.compact) {
+        (IIIFVersion::VERSION3, true) => builder.to_manifest3().to_json()?,
+        (IIIFVersion::VERSION3, false) => builder.to_manifest3().to_json_pretty()?,
+        (IIIFVersion::VERSION211, true) => builder.to_manifest2().to_json()?,
+        (IIIFVersion::VERSION211, false) => builder.to_manifest2().to_json_pretty()?,
+    };
+    // the folder has no images of its own on disk when identifiers don't mirror it
+    create_dir_all(dir)?;
+    let file_path = dir.join("manifest.json");
+    info!("Writing manifest: {}", file_path.display());
+    let mut file = File::create(file_path)?;
+    file.write_all(json.as_bytes())?;
+    Ok(())
+}
+
+// works out whether the file is an image from its contents, so any extension or none will do.
+// Files with an image extension count too, so a corrupt one is reported rather than skipped.
+fn is_image_file(path: &Path) -> bool {
+    detect_format(path).is_some() || ImageFormat::from_path(path).is_ok()
+}
+
+// Tiles the image with the given identifier, returning each version's info.json
+fn process_image(
+    args: &Arguments,
+    path: &Path,
+    id: &str,
+    iiif_versions: &[IIIFVersion],
+) -> Result<Vec<(IIIFVersion, String)>, Error> {
+    // streaming only reads 8-bit RGB as it's stored
+    let upright = args.ignore_orientation
+        || read_orientation(path).is_ok_and(|orientation| orientation == Orientation::NoTransforms);
+    let icc_profile = match args.icc_profile {
+        IccHandling::Ignore => None,
+        _ => read_icc_profile(path).unwrap_or(None),
+    };
+    let srgb = args.icc_profile != IccHandling::Convert || icc_profile.is_none();
+    let manifests =
+        if args.streaming && !args.preserve_pixels && upright && srgb && can_stream(path) {
+            info!("Streaming image from: {}", path.display());
+            let (width, height) = image::image_dimensions(path)?;
+            let mut info = ImageInfo::from_dimensions(
+                id,
+                width as i32,
+                height as i32,
+                args.tile_size,
+                args.tile_size,
+                args.zoom_levels,
+            );
+            fit_to_max_files(args, &mut info, iiif_versions)?;
 
-    let info = ImageInfo::new(&img, args.tile_size, args.tile_size, args.zoom_levels);
+            let mut tiler = build_tiler(args, &info, iiif_versions, load_properties(args, path)?);
+            if args.icc_profile == IccHandling::Embed {
+                tiler.set_icc_profile(icc_profile);
+            }
+            let manifests = tiler.create_streaming_all(path, &args.output_dir, &args.uri)?;
+            write_manifests(args, &tiler, &manifests)?;
+            manifests
+        } else {
+            info!("Loading image from: {}", path.display());
+            if args.streaming && !upright {
+                info!("Decoding in memory to apply the EXIF orientation");
+            }
+            if args.streaming && !srgb {
+                info!("Decoding in memory to convert to sRGB");
+            }
+            let mut img = IIIFImage::open_with_options(path, &load_options(args))?;
+            img.set_id(id);
+            tile_image(args, path, &img, iiif_versions)?
+        };
 
-    let manifest = Tiler::create_image(&info, &args.output_dir, &args.uri, iiif_version)?;
-    write_manifest(args, &info, &manifest)?;
+    info!("Successfully processed image: {}", path.display());
+    Ok(manifests)
+}
 
-    info!("Successfully processed image: {}", img_path);
+// Tiles each page of a multi-page TIFF or animated GIF as its own image, returning each page's
+// identifier and each version's info.json. Page identifiers are claimed in `ids` as they're
+// decoded, so a page can't overwrite another image's tiles.
+fn process_pages(
+    args: &Arguments,
+    path: &Path,
+    id: &str,
+    iiif_versions: &[IIIFVersion],
+    ids: &mut HashMap<String, PathBuf>,
+) -> Result<Vec<TiledImage>, Error> {
+    info!("Loading pages from: {}", path.display());
+    let mut pages = IIIFImage::open_pages(path, &load_options(args))?;
+    pages.set_id(id);
+
+    let mut tiled = Vec::new();
+    for page in pages {
+        let page = page?;
+        let page_id = page.id();
+        if page_id != id {
+            claim_id(ids, &page_id, path)?;
+        }
+        let manifests = tile_image(args, path, &page, iiif_versions)?;
+        tiled.push((page_id, manifests));
+    }
+    if tiled.is_empty() {
+        return Err(Error::msg(format!("No pages in {}", path.display())));
+    }
+    info!(
+        "Successfully processed {} page(s) of: {}",
+        tiled.len(),
+        path.display()
+    );
+    Ok(tiled)
+}
+
+// whether the file's format can hold several pages or frames
+fn has_pages(path: &Path) -> bool {
+    matches!(
+        detect_format(path),
+        Some(ImageFormat::Tiff | ImageFormat::Gif)
+    )
+}
+
+// records the identifier as given to the image, unless another image already has it
+fn claim_id(ids: &mut HashMap<String, PathBuf>, id: &str, path: &Path) -> Result<(), Error> {
+    // two images with the same identifier would overwrite each other's tiles
+    if let Some(other) = ids.get(id) {
+        return Err(Error::msg(format!(
+            "Identifier '{}' is already used by {}",
+            id,
+            other.display()
+        )));
+    }
+    ids.insert(id.to_string(), path.to_path_buf());
     Ok(())
 }
 
-fn write_manifest(args: &Arguments, info: &ImageInfo, manifest: &str) -> Result<(), Error> {
-    let file_path = format!("{}/{}/info.json", args.output_dir, info.id());
-    let file = File::create(file_path)?;
-    let json_manifest: Value = serde_json::from_str(manifest)?;
+// the decoding settings the command line asks for
+fn load_options(args: &Arguments) -> LoadOptions {
+    LoadOptions {
+        pixels: if args.preserve_pixels {
+            PixelMode::Preserve
+        } else {
+            PixelMode::Rgb8
+        },
+        orientation: if args.ignore_orientation {
+            OrientationMode::Ignore
+        } else {
+            OrientationMode::Apply
+        },
+        icc: if args.icc_profile == IccHandling::Convert {
+            IccMode::ConvertToSrgb
+        } else {
+            IccMode::Keep
+        },
+        tone_mapping: args.tone_mapping.into(),
+        ..LoadOptions::default()
+    }
+}
 
-    // Write the pretty-printed JSON to the file
-    to_writer_pretty(file, &json_manifest)?;
+// Tiles a decoded image, reading the properties from the sidecar next to `path`
+fn tile_image(
+    args: &Arguments,
+    path: &Path,
+    img: &IIIFImage,
+    iiif_versions: &[IIIFVersion],
+) -> Result<Vec<(IIIFVersion, String)>, Error> {
+    let mut info = ImageInfo::new(img, args.tile_size, args.tile_size, args.zoom_levels);
+    fit_to_max_files(args, &mut info, iiif_versions)?;
+
+    let mut tiler = build_tiler(args, &info, iiif_versions, load_properties(args, path)?);
+    if args.icc_profile == IccHandling::Embed {
+        tiler.set_icc_profile(img.icc_profile().map(<[u8]>::to_vec));
+    }
+    // the info.json is only written once every tile has been written
+    let manifests = tiler.create_all(&args.output_dir, &args.uri)?;
+    write_manifests(args, &tiler, &manifests)?;
+    Ok(manifests)
+}
+
+// picks the tile size and zoom levels for --max-files, sharing the files out between the
+// versions and the variants of each tile and size
+fn fit_to_max_files(
+    args: &Arguments,
+    info: &mut ImageInfo,
+    iiif_versions: &[IIIFVersion],
+) -> Result<(), Error> {
+    if let Some(max_files) = args.max_files {
+        let (tile_variants, size_variants) = variant_counts(args);
+        // sizes are rotated even when tiles aren't. Room is kept for the rotations of the most
+        // sizes fitting can pick, every one of four zoom levels plus max.
+        let size_rotation_files = size_entry_files(6) * (size_variants - tile_variants);
+        let max_counted =
+            (max_files / iiif_versions.len() as i32 - size_rotation_files) / tile_variants;
+        info.try_fit_to_max_file_no(max_counted).with_context(|| {
+            format!(
+                "--max-files {} leaves {} for each version's tiles in one format, quality and rotation",
+                max_files, max_counted
+            )
+        })?;
+    }
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let args = Arguments::parse();
+// how many times each tile and each size is written, once for every format and quality, and
+// for every rotation of sizes and, with --rotate-tiles, of tiles
+fn variant_counts(args: &Arguments) -> (i32, i32) {
+    let formats = level0_formats(args.formats.clone()).len().max(1);
+    let formats_and_qualities = formats * (1 + args.qualities.len());
+    let rotations = 1 + args.rotations.len();
+    let tile_rotations = if args.rotate_tiles { rotations } else { 1 };
+    (
+        (formats_and_qualities * tile_rotations) as i32,
+        (formats_and_qualities * rotations) as i32,
+    )
+}
+
+// the files and directories ImageInfo counts for this many sizes, each a size directory with a
+// rotation directory and image under it
+fn size_entry_files(sizes: i32) -> i32 {
+    sizes * 3
+}
+
+// at most how many files and directories tiling the image writes for one version, every file
+// ImageInfo counts being multiplied by the tile variants and the sizes' extra rotations added
+fn estimated_file_count(args: &Arguments, info: &ImageInfo) -> u64 {
+    let (tile_variants, size_variants) = variant_counts(args);
+    // every scale factor's size plus max
+    let sizes = info.get_scale_factors().len() as i32 + 1;
+    (info.calculate_file_count() * tile_variants
+        + size_entry_files(sizes) * (size_variants - tile_variants)) as u64
+}
+
+// Prints the files, pixels and approximate bytes tiling the image or directory would write,
+// without writing anything. Returns the images that couldn't be read.
+fn estimate(
+    args: &Arguments,
+    path: &Path,
+    iiif_versions: &[IIIFVersion],
+) -> Result<Vec<(PathBuf, Error)>, Error> {
+    let mut images = Vec::new();
+    if path.is_dir() {
+        find_images(args, path, Path::new(""), &mut images)?;
+    } else {
+        images.push(path.to_path_buf());
+    }
+
+    let format = args.formats.first().copied().unwrap_or_default();
+    let mut failures = Vec::new();
+    let (mut total_files, mut total_pixels) = (0, 0);
+    for image in images {
+        let infos = page_infos(args, &image, iiif_versions);
+        match infos {
+            Ok(infos) => {
+                let several = infos.len() > 1;
+                for (page, info) in infos.iter().enumerate() {
+                    let label = if several {
+                        format!("{} p. {}", image.display(), page + 1)
+                    } else {
+                        image.display().to_string()
+                    };
+                    let files = estimated_file_count(args, info) * iiif_versions.len() as u64;
+                    let pixels = info.calculate_pixel_count();
+                    println!(
+                        "{}: {}x{}, {} zoom levels of {} pixel tiles, {} files, {} pixels, about {}",
+                        label,
+                        info.get_width(),
+                        info.get_height(),
+                        info.get_scale_factors().len() - 1,
+                        info.get_tile_width(),
+                        files,
+                        pixels,
+                        format_bytes(pixels as f64 * bytes_per_pixel(args, format))
+                    );
+                    total_files += files;
+                    total_pixels += pixels;
+                }
+            }
+            Err(e) => {
+                error!("Failed to read {}: {:#}", image.display(), e);
+                failures.push((image, e));
+            }
+        }
+    }
+    println!(
+        "Total: {} files, {} pixels, about {}",
+        total_files,
+        total_pixels,
+        format_bytes(total_pixels as f64 * bytes_per_pixel(args, format))
+    );
+    Ok(failures)
+}
+
+// the image info of each page estimate counts, every page with --pages and the first otherwise
+fn page_infos<'a>(
+    args: &Arguments,
+    path: &Path,
+    iiif_versions: &[IIIFVersion],
+) -> Result<Vec<ImageInfo<'a>>, Error> {
+    let orientation = load_options(args).orientation;
+    let pages = if args.pages {
+        read_page_dimensions(path, orientation)?
+    } else {
+        vec![read_dimensions(path, orientation)?]
+    };
+    let file_id = id_from_path(path)?;
+    let mut infos = Vec::new();
+    for (page, (width, height)) in pages.iter().enumerate() {
+        let id = if pages.len() > 1 {
+            page_id(&file_id, page + 1)
+        } else {
+            file_id.clone()
+        };
+        let mut info = ImageInfo::from_dimensions(
+            &id,
+            *width as i32,
+            *height as i32,
+            args.tile_size,
+            args.tile_size,
+            args.zoom_levels,
+        );
+        fit_to_max_files(args, &mut info, iiif_versions)?;
+        infos.push(info);
+    }
+    Ok(infos)
+}
+
+// rough bytes per pixel of photographs in each output format, only good for estimates
+fn bytes_per_pixel(args: &Arguments, format: OutputFormat) -> f64 {
+    match format {
+        OutputFormat::Jpg => 0.1 + 0.3 * (f64::from(args.jpeg_quality) / 100.0).powi(2),
+        OutputFormat::Png => 1.5,
+        OutputFormat::Webp if args.webp_quality.is_some() => 0.15,
+        OutputFormat::Webp => 1.2,
+        OutputFormat::Tif => 3.0,
+        OutputFormat::Gif => 0.8,
+    }
+}
+
+// formats a byte count with a decimal unit, e.g. `3.2 MB`
+fn format_bytes(bytes: f64) -> String {
+    let units = ["bytes", "KB", "MB", "GB", "TB"];
+    let mut size = bytes;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < units.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{:.0} {}", size, units[unit])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+// builds each version's manifest of a multi-page file, with a canvas per page. It lives in a
+// folder named after the file's identifier, next to its pages' folders.
+fn page_manifests(
+    args: &Arguments,
+    iiif_versions: &[IIIFVersion],
+    id: &str,
+    label: &str,
+    pages: &[TiledImage],
+) -> Result<Vec<ManifestBuilder>, Error> {
+    let mut builders = Vec::new();
+    for (index, version) in iiif_versions.iter().enumerate() {
+        let (_, uri) = version_root(args, iiif_versions, version);
+        let mut builder = ManifestBuilder::new(&format!("{}{}/", uri, encode_id(id)), label);
+        for (page, (_, manifests)) in pages.iter().enumerate() {
+            let (_, manifest) = &manifests[index];
+            builder.add_canvas(
+                &format!("p. {}", page + 1),
+                ImageService::from_json(manifest)?,
+            );
+        }
+        builders.push(builder);
+    }
+    Ok(builders)
+}
+
+// Reads the info.json properties from the image's sidecar `<name>.json` or `<name>.toml`
+// if it has one, filling in the rights from the command line
+fn load_properties(args: &Arguments, img_path: &Path) -> Result<Properties, Error> {
+    let mut properties = Properties::default();
+    for extension in ["json", "toml"] {
+        let sidecar = img_path.with_extension(extension);
+        if sidecar.is_file() {
+            info!("Reading properties from: {}", sidecar.display());
+            let text = read_to_string(&sidecar)?;
+            let parsed = if extension == "json" {
+                Properties::from_json(&text)
+            } else {
+                Properties::from_toml(&text)
+            };
+            properties = parsed.with_context(|| format!("Failed to read {}", sidecar.display()))?;
+            break;
+        }
+    }
+    if properties.rights.is_none() {
+        properties.rights = args.rights.clone();
+    }
+    Ok(properties)
+}
+
+fn build_tiler<'a>(
+    args: &Arguments,
+    info: &'a ImageInfo,
+    iiif_versions: &'a [IIIFVersion],
+    properties: Properties,
+) -> Tiler<'a> {
+    let mut tiler = Tiler::new(info, &iiif_versions[0]);
+    tiler.set_versions(iiif_versions.to_vec());
+    tiler.set_properties(properties);
+    tiler.set_fail_fast(args.fail_fast);
+    tiler.set_threads(args.threads);
+    if args.pyramid {
+        tiler.set_mode(TilingMode::Pyramid);
+    }
+    tiler.set_filter(args.filter.into());
+    tiler.set_memory_budget(args.memory_budget * 1024 * 1024);
+    if !args.formats.is_empty() {
+        tiler.set_formats(args.formats.clone());
+    }
+    tiler.set_pretty(!args.compact);
+    tiler.set_qualities(args.qualities.clone());
+    tiler.set_rotations(args.rotations.clone());
+    tiler.set_rotate_tiles(args.rotate_tiles);
+    tiler.set_bitonal(if args.dither {
+        Bitonal::Dither
+    } else {
+        Bitonal::Threshold(args.bitonal_threshold)
+    });
+    tiler.set_encoder_settings(EncoderSettings {
+        jpeg_quality: args.jpeg_quality,
+        jpeg_progressive: args.progressive,
+        chroma_subsampling: args.chroma_subsampling,
+        png_compression: args.png_compression,
+        webp_quality: args.webp_quality,
+        background: args.background,
+    });
+    tiler
+}
+
+// writes each version's info.json into its service directory
+fn write_manifests(
+    args: &Arguments,
+    tiler: &Tiler,
+    manifests: &[(IIIFVersion, String)],
+) -> Result<(), Error> {
+    for (version, manifest) in manifests {
+        let file_path = tiler
+            .service_dir(&args.output_dir, version)
+            .join("info.json");
+        let mut file = File::create(file_path)?;
+        file.write_all(manifest.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn main() -> Result<ExitCode> {
+    let cli = Cli::parse();
+    let (args, estimating) = match cli.command {
+        Some(Command::Estimate(args)) => (args, true),
+        None => (
+            cli.args.expect("clap requires a path without a subcommand"),
+            false,
+        ),
+    };
 
     if args.verbose {
         pretty_env_logger::formatted_builder()
@@ -131,28 +1102,77 @@ fn main() -> Result<()> {
             .init();
     }
 
-    // determine which IIIF version we're working with
-    let iiif_version = match args.iiif_version.as_str() {
-        "2" => Ok(IIIFVersion::VERSION211),
-        "3" => Ok(IIIFVersion::VERSION3),
-        _ => Err(Error::msg(format!(
-            "Unrecognized IIIF version: '{}'. Please provide '2' or '3'.",
-            args.iiif_version
-        ))),
-    }?;
+    // determine which IIIF versions we're working with
+    let mut iiif_versions = Vec::new();
+    for version in args.iiif_version.split(',') {
+        let iiif_version = match version.trim() {
+            "2" => Ok(IIIFVersion::VERSION211),
+            "3" => Ok(IIIFVersion::VERSION3),
+            _ => Err(Error::msg(format!(
+                "Unrecognized IIIF version: '{}'. Please provide '2', '3' or '2,3'.",
+                args.iiif_version
+            ))),
+        }?;
+        if !iiif_versions.contains(&iiif_version) {
+            iiif_versions.push(iiif_version);
+        }
+    }
 
     let path = Path::new(args.path.as_str());
 
-    if path.is_file() {
-        process_image(&args, args.path.as_str(), &iiif_version)?;
+    let failures = if estimating && path.exists() {
+        estimate(&args, path, &iiif_versions)?
+    } else if path.is_file() {
+        let root = path.parent().unwrap_or(Path::new(""));
+        let id = identifier_strategy(&args, root)?.identify(path)?;
+        let result = if args.pages && has_pages(path) {
+            process_pages(&args, path, &id, &iiif_versions, &mut HashMap::new())
+        } else {
+            process_image(&args, path, &id, &iiif_versions)
+                .map(|manifests| vec![(id.clone(), manifests)])
+        };
+        match result {
+            Ok(pages) if args.manifest && pages.len() > 1 => {
+                // the pages' manifest sits next to their folders
+                let label = id_from_path(path)?;
+                let builders = page_manifests(&args, &iiif_versions, &id, &label, &pages)?;
+                for (version, builder) in iiif_versions.iter().zip(&builders) {
+                    let (dir, _) = version_root(&args, &iiif_versions, version);
+                    write_presentation(&args, version, builder, &dir.join(&id))?;
+                }
+                Vec::new()
+            }
+            Ok(pages) if args.manifest => {
+                // the image's manifest sits next to its info.json
+                for (version, manifest) in &pages[0].1 {
+                    let service = ImageService::from_json(manifest)?;
+                    let mut builder = ManifestBuilder::new(&format!("{}/", service.id()), &id);
+                    builder.add_canvas(&id, service);
+                    let (dir, _) = version_root(&args, &iiif_versions, version);
+                    write_presentation(&args, version, &builder, &dir.join(&id))?;
+                }
+                Vec::new()
+            }
+            Ok(_) => Vec::new(),
+            Err(e) => vec![(path.to_path_buf(), e)],
+        }
     } else if path.is_dir() {
-        process_directory(&args, path.to_str().unwrap(), &iiif_version)?;
+        process_directory(&args, path.to_str().unwrap(), &iiif_versions)?
     } else {
         println!(
             "{:?} does not exist or is neither a file nor a directory.",
             path
         );
+        Vec::new()
+    };
+
+    if failures.is_empty() {
+        return Ok(ExitCode::SUCCESS);
     }
 
-    Ok(())
+    eprintln!("Failed to process {} image(s):", failures.len());
+    for (failed_path, e) in &failures {
+        eprintln!("  {}: {:#}", failed_path.display(), e);
+    }
+    Ok(ExitCode::FAILURE)
 }
@@ -0,0 +1,687 @@
+/**
+ * Settings for how tiles and sizes are written out
+ */
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+
+use image::codecs::png::{CompressionType, FilterType as PngFilter, PngEncoder};
+use image::codecs::webp::WebPEncoder;
+use image::error::{EncodingError, ImageFormatHint};
+use image::imageops::{self, BiLevel};
+use image::{
+    ColorType as ImageColorType, DynamicImage, ImageEncoder, ImageError, ImageFormat, RgbImage,
+};
+use jpeg_encoder::{ColorType, Encoder as JpegEncoder, SamplingFactor};
+use log::warn;
+use moxcms::ColorProfile;
+
+use crate::error::TilerError;
+
+/// An image format tiles can be written in. Each one is written as `default.<extension>`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum OutputFormat {
+    #[default]
+    Jpg,
+    Png,
+    Webp,
+    Tif,
+    Gif,
+}
+
+impl OutputFormat {
+    /// The file extension, which is also the format's name in the IIIF Image API
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpg => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Tif => "tif",
+            OutputFormat::Gif => "gif",
+        }
+    }
+
+    /// The media type, used as the `format` of images in a manifest
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Tif => "image/tiff",
+            OutputFormat::Gif => "image/gif",
+        }
+    }
+
+    pub fn image_format(&self) -> ImageFormat {
+        match self {
+            OutputFormat::Jpg => ImageFormat::Jpeg,
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Webp => ImageFormat::WebP,
+            OutputFormat::Tif => ImageFormat::Tiff,
+            OutputFormat::Gif => ImageFormat::Gif,
+        }
+    }
+
+    /// The widest or tallest image the format can hold
+    pub fn max_dimension(&self) -> u32 {
+        match self {
+            OutputFormat::Jpg | OutputFormat::Gif => u32::from(u16::MAX),
+            OutputFormat::Png => i32::MAX as u32,
+            OutputFormat::Webp => 16383,
+            OutputFormat::Tif => u32::MAX,
+        }
+    }
+}
+
+/// Whether every one of the formats can hold an image of this size, whichever way round it's
+/// rotated. Sizes that don't fit are left out rather than written in only some formats.
+pub fn fits_formats(formats: &[OutputFormat], (width, height): (i32, i32)) -> bool {
+    let longest = width.max(height).max(0) as u32;
+    formats
+        .iter()
+        .all(|format| longest <= format.max_dimension())
+}
+
+/// Adds jpg after the given formats when they don't include it, as IIIF level 0 needs every
+/// tile and size in jpg
+pub fn level0_formats(mut formats: Vec<OutputFormat>) -> Vec<OutputFormat> {
+    if !formats.is_empty() && !formats.contains(&OutputFormat::Jpg) {
+        formats.push(OutputFormat::Jpg);
+    }
+    formats
+}
+
+impl FromStr for OutputFormat {
+    type Err = TilerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "jpg" | "jpeg" => Ok(OutputFormat::Jpg),
+            "png" => Ok(OutputFormat::Png),
+            "webp" => Ok(OutputFormat::Webp),
+            "tif" | "tiff" => Ok(OutputFormat::Tif),
+            "gif" => Ok(OutputFormat::Gif),
+            _ => Err(TilerError::InvalidParameter(format!(
+                "Unrecognized output format: '{}'. Please provide jpg, png, webp, tif or gif.",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+/// A quality written alongside `default`, e.g. `gray.jpg` next to `default.jpg`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Quality {
+    Gray,
+    Bitonal,
+}
+
+impl Quality {
+    /// The quality's name in the IIIF Image API, which is also its file name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Quality::Gray => "gray",
+            Quality::Bitonal => "bitonal",
+        }
+    }
+
+    // renders the quality from the default image
+    pub(crate) fn render(&self, image: &DynamicImage, bitonal: Bitonal) -> DynamicImage {
+        match self {
+            Quality::Gray => image.grayscale(),
+            Quality::Bitonal => {
+                let mut gray = image.to_luma8();
+                match bitonal {
+                    Bitonal::Threshold(threshold) => {
+                        for pixel in gray.pixels_mut() {
+                            pixel.0[0] = if pixel.0[0] >= threshold { 255 } else { 0 };
+                        }
+                    }
+                    Bitonal::Dither => imageops::dither(&mut gray, &BiLevel),
+                }
+                DynamicImage::ImageLuma8(gray)
+            }
+        }
+    }
+}
+
+impl FromStr for Quality {
+    type Err = TilerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "gray" | "grey" => Ok(Quality::Gray),
+            "bitonal" => Ok(Quality::Bitonal),
+            _ => Err(TilerError::InvalidParameter(format!(
+                "Unrecognized quality: '{}'. Please provide gray or bitonal.",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A rotation written alongside `0`. `Mirror` is `!0`, flipped left to right.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Rotation {
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    Mirror,
+}
+
+impl Rotation {
+    /// The rotation's directory name in the IIIF Image API
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rotation::Rotate90 => "90",
+            Rotation::Rotate180 => "180",
+            Rotation::Rotate270 => "270",
+            Rotation::Mirror => "!0",
+        }
+    }
+
+    pub(crate) fn render(&self, image: &DynamicImage) -> DynamicImage {
+        match self {
+            Rotation::Rotate90 => image.rotate90(),
+            Rotation::Rotate180 => image.rotate180(),
+            Rotation::Rotate270 => image.rotate270(),
+            Rotation::Mirror => image.fliph(),
+        }
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = TilerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "90" => Ok(Rotation::Rotate90),
+            "180" => Ok(Rotation::Rotate180),
+            "270" => Ok(Rotation::Rotate270),
+            "!0" => Ok(Rotation::Mirror),
+            _ => Err(TilerError::InvalidParameter(format!(
+                "Unrecognized rotation: '{}'. Please provide 90, 180, 270 or !0.",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Rotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// How the bitonal quality is reduced to black and white
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Bitonal {
+    /// Pixels at least this bright become white, the rest black.
+    Threshold(u8),
+    /// Floyd-Steinberg dithering. Each tile and size is dithered on its own.
+    Dither,
+}
+
+impl Default for Bitonal {
+    fn default() -> Self {
+        Bitonal::Threshold(128)
+    }
+}
+
+/// How much of the colour detail a JPEG keeps relative to its brightness
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ChromaSubsampling {
+    /// 4:4:4, colour at full resolution
+    Cs444,
+    /// 4:2:2, colour at half the horizontal resolution
+    Cs422,
+    /// 4:2:0, colour at half the horizontal and vertical resolution
+    #[default]
+    Cs420,
+}
+
+impl FromStr for ChromaSubsampling {
+    type Err = TilerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "444" | "4:4:4" => Ok(ChromaSubsampling::Cs444),
+            "422" | "4:2:2" => Ok(ChromaSubsampling::Cs422),
+            "420" | "4:2:0" => Ok(ChromaSubsampling::Cs420),
+            _ => Err(TilerError::InvalidParameter(format!(
+                "Unrecognized chroma subsampling: '{}'. Please provide 444, 422 or 420.",
+                s
+            ))),
+        }
+    }
+}
+
+/// How hard the PNG encoder works to shrink files
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum PngCompression {
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+
+impl FromStr for PngCompression {
+    type Err = TilerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fast" => Ok(PngCompression::Fast),
+            "default" => Ok(PngCompression::Default),
+            "best" => Ok(PngCompression::Best),
+            _ => Err(TilerError::InvalidParameter(format!(
+                "Unrecognized PNG compression: '{}'. Please provide fast, default or best.",
+                s
+            ))),
+        }
+    }
+}
+
+/// Encoder settings for the output formats. Formats without settings are written with the `image` crate's defaults.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct EncoderSettings {
+    /// JPEG quality from 1 to 100
+    pub jpeg_quality: u8,
+    /// Write progressive JPEGs that render coarse to fine as they download
+    pub jpeg_progressive: bool,
+    pub chroma_subsampling: ChromaSubsampling,
+    pub png_compression: PngCompression,
+    /// Lossy WebP quality from 0 to 100. `None` writes lossless WebP.
+    pub webp_quality: Option<u8>,
+    /// RGB colour transparent pixels are flattened onto when writing JPEGs
+    pub background: [u8; 3],
+}
+
+impl Default for EncoderSettings {
+    fn default() -> Self {
+        EncoderSettings {
+            jpeg_quality: 75,
+            jpeg_progressive: false,
+            chroma_subsampling: ChromaSubsampling::Cs420,
+            png_compression: PngCompression::Default,
+            webp_quality: None,
+            background: [255, 255, 255],
+        }
+    }
+}
+
+impl EncoderSettings {
+    pub(crate) fn check(&self) -> Result<(), TilerError> {
+        check_range("JPEG quality", 1..=100, self.jpeg_quality)?;
+        if let Some(quality) = self.webp_quality {
+            check_range("WebP quality", 0..=100, quality)?;
+            if !cfg!(feature = "lossy-webp") {
+                return Err(lossy_webp_disabled());
+            }
+        }
+        Ok(())
+    }
+
+    // encodes the image to path in the given format with these settings. The ICC profile for the
+    // encoded pixels is embedded in JPEG, PNG and WebP files and left out of the others.
+    pub(crate) fn save(
+        &self,
+        image: &DynamicImage,
+        path: &Path,
+        format: OutputFormat,
+        icc_profiles: &IccProfiles,
+    ) -> Result<(), TilerError> {
+        let image = &*self.fit_to_format(image, format);
+        // WebP has no grey layout, its grey pixels are stored as RGB
+        let icc_profile =
+            icc_profiles.get(!image.color().has_color() && format != OutputFormat::Webp);
+        let unsupported = |e| TilerError::from_save(path, ImageError::Unsupported(e));
+        match format {
+            OutputFormat::Jpg => self.save_jpeg(image, path, icc_profile),
+            OutputFormat::Png => {
+                let compression = match self.png_compression {
+                    PngCompression::Fast => CompressionType::Fast,
+                    PngCompression::Default => CompressionType::Default,
+                    PngCompression::Best => CompressionType::Best,
+                };
+                let mut writer = create_file(path)?;
+                let mut encoder =
+                    PngEncoder::new_with_quality(&mut writer, compression, PngFilter::Adaptive);
+                if let Some(icc_profile) = icc_profile {
+                    encoder
+                        .set_icc_profile(icc_profile.to_vec())
+                        .map_err(unsupported)?;
+                }
+                image
+                    .write_with_encoder(encoder)
+                    .map_err(|e| TilerError::from_save(path, e))?;
+                flush(writer, path)
+            }
+            OutputFormat::Webp => match self.webp_quality {
+                #[cfg(not(feature = "lossy-webp"))]
+                Some(_) => Err(lossy_webp_disabled()),
+                #[cfg(feature = "lossy-webp")]
+                Some(quality) => {
+                    let encoded = if image.color().has_alpha() {
+                        let rgba = image.to_rgba8();
+                        webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                            .encode(quality as f32)
+                    } else {
+                        let rgb = image.to_rgb8();
+                        webp::Encoder::from_rgb(&rgb, rgb.width(), rgb.height())
+                            .encode(quality as f32)
+                    };
+                    let mut writer = create_file(path)?;
+                    let written = match icc_profile {
+                        Some(icc_profile) => writer.write_all(&with_icc_profile(
+                            &encoded,
+                            icc_profile,
+                            image.width(),
+                            image.height(),
+                        )),
+                        None => writer.write_all(&encoded),
+                    };
+                    written.map_err(|e| TilerError::Io {
+                        path: path.to_path_buf(),
+                        source: e,
+                    })?;
+                    flush(writer, path)
+                }
+                None => {
+                    let mut writer = create_file(path)?;
+                    let mut encoder = WebPEncoder::new_lossless(&mut writer);
+                    if let Some(icc_profile) = icc_profile {
+                        encoder
+                            .set_icc_profile(icc_profile.to_vec())
+                            .map_err(unsupported)?;
+                    }
+                    image
+                        .write_with_encoder(encoder)
+                        .map_err(|e| TilerError::from_save(path, e))?;
+                    flush(writer, path)
+                }
+            },
+            OutputFormat::Tif | OutputFormat::Gif => image
+                .save_with_format(path, format.image_format())
+                .map_err(|e| TilerError::from_save(path, e)),
+        }
+    }
+
+    // converts the image to the closest colour type the format can hold, keeping it as is when it already fits
+    fn fit_to_format<'i>(
+        &self,
+        image: &'i DynamicImage,
+        format: OutputFormat,
+    ) -> Cow<'i, DynamicImage> {
+        let color = image.color();
+        let gray = !color.has_color();
+        let alpha = color.has_alpha();
+        let deep = color.bytes_per_pixel() > color.channel_count();
+
+        let target = match format {
+            OutputFormat::Jpg if alpha => {
+                return Cow::Owned(DynamicImage::ImageRgb8(self.flatten(image)))
+            }
+            OutputFormat::Jpg => color_type(gray, false, false),
+            OutputFormat::Png => color_type(gray, alpha, deep),
+            OutputFormat::Webp => color_type(gray, alpha, false),
+            // TIFF has no grey and alpha layout
+            OutputFormat::Tif => color_type(gray && !alpha, alpha, deep),
+            OutputFormat::Gif => color_type(false, alpha, false),
+        };
+        if target == color {
+            return Cow::Borrowed(image);
+        }
+        Cow::Owned(match target {
+            ImageColorType::L8 => DynamicImage::ImageLuma8(image.to_luma8()),
+            ImageColorType::La8 => DynamicImage::ImageLumaA8(image.to_luma_alpha8()),
+            ImageColorType::Rgb8 => DynamicImage::ImageRgb8(image.to_rgb8()),
+            ImageColorType::Rgba8 => DynamicImage::ImageRgba8(image.to_rgba8()),
+            ImageColorType::L16 => DynamicImage::ImageLuma16(image.to_luma16()),
+            ImageColorType::La16 => DynamicImage::ImageLumaA16(image.to_luma_alpha16()),
+            ImageColorType::Rgb16 => DynamicImage::ImageRgb16(image.to_rgb16()),
+            _ => DynamicImage::ImageRgba16(image.to_rgba16()),
+        })
+    }
+
+    // blends the image over the background colour
+    fn flatten(&self, image: &DynamicImage) -> RgbImage {
+        let rgba = image.to_rgba8();
+        RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+            let [r, g, b, a] = rgba.get_pixel(x, y).0;
+            let blend = |channel: u8, background: u8| {
+                let (channel, background, a) = (channel as u32, background as u32, a as u32);
+                ((channel * a + background * (255 - a) + 127) / 255) as u8
+            };
+            image::Rgb([
+                blend(r, self.background[0]),
+                blend(g, self.background[1]),
+                blend(b, self.background[2]),
+            ])
+        })
+    }
+
+    fn save_jpeg(
+        &self,
+        image: &DynamicImage,
+        path: &Path,
+        icc_profile: Option<&[u8]>,
+    ) -> Result<(), TilerError> {
+        let (width, height) = match (u16::try_from(image.width()), u16::try_from(image.height())) {
+            (Ok(width), Ok(height)) => (width, height),
+            _ => {
+                return Err(encode_error(
+                    path,
+                    format!(
+                        "{}x{} is larger than a JPEG can hold",
+                        image.width(),
+                        image.height()
+                    ),
+                ))
+            }
+        };
+        // grayscale stays single channel, everything else is written as 8-bit RGB
+        let (pixels, color_type) = match image {
+            DynamicImage::ImageLuma8(gray) => (Cow::Borrowed(gray.as_raw()), ColorType::Luma),
+            DynamicImage::ImageRgb8(rgb) => (Cow::Borrowed(rgb.as_raw()), ColorType::Rgb),
+            _ => (Cow::Owned(image.to_rgb8().into_raw()), ColorType::Rgb),
+        };
+
+        let mut writer = create_file(path)?;
+        let mut encoder = JpegEncoder::new(&mut writer, self.jpeg_quality);
+        encoder.set_progressive(self.jpeg_progressive);
+        encoder.set_sampling_factor(match self.chroma_subsampling {
+            ChromaSubsampling::Cs444 => SamplingFactor::R_4_4_4,
+            ChromaSubsampling::Cs422 => SamplingFactor::R_4_2_2,
+            ChromaSubsampling::Cs420 => SamplingFactor::R_4_2_0,
+        });
+        let jpeg_error = |e| match e {
+            jpeg_encoder::EncodingError::IoError(source) => TilerError::Io {
+                path: path.to_path_buf(),
+                source,
+            },
+            e => encode_error(path, e),
+        };
+        if let Some(icc_profile) = icc_profile {
+            encoder.add_icc_profile(icc_profile).map_err(jpeg_error)?;
+        }
+        encoder
+            .encode(&pixels, width, height, color_type)
+            .map_err(jpeg_error)?;
+        flush(writer, path)
+    }
+}
+
+/// The source's ICC profile for RGB pixels and for grey ones, so each file is written with a
+/// profile of its own colour space.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct IccProfiles {
+    rgb: Option<Vec<u8>>,
+    gray: Option<Vec<u8>>,
+}
+
+impl IccProfiles {
+    // keeps the profile for pixels of its own colour space and derives one with the same tone curve
+    // for the other. Profiles for any other colour space, e.g. CMYK, no longer describe the pixels
+    // once decoded and are dropped.
+    pub(crate) fn new(icc_profile: Option<Vec<u8>>) -> IccProfiles {
+        let Some(icc_profile) = icc_profile else {
+            return IccProfiles::default();
+        };
+        let parsed = ColorProfile::new_from_slice(&icc_profile).ok();
+        match icc_profile.get(16..20) {
+            Some(b"RGB ") => IccProfiles {
+                gray: parsed.as_ref().and_then(gray_profile),
+                rgb: Some(icc_profile),
+            },
+            Some(b"GRAY") => IccProfiles {
+                rgb: parsed.as_ref().and_then(rgb_profile),
+                gray: Some(icc_profile),
+            },
+            _ => {
+                warn!("Not embedding the ICC profile, it's for neither RGB nor grey pixels");
+                IccProfiles::default()
+            }
+        }
+    }
+
+    fn get(&self, gray: bool) -> Option<&[u8]> {
+        if gray {
+            self.gray.as_deref()
+        } else {
+            self.rgb.as_deref()
+        }
+    }
+}
+
+// a grey profile with the RGB profile's green tone curve, which carries most of the luminance.
+// Profiles built from lookup tables rather than curves have none to take.
+fn gray_profile(rgb: &ColorProfile) -> Option<Vec<u8>> {
+    let mut gray = ColorProfile::new_gray_with_gamma(2.2);
+    gray.gray_trc = Some(rgb.green_trc.clone()?);
+    gray.media_white_point = rgb.media_white_point;
+    gray.description = rgb.description.clone();
+    gray.copyright = rgb.copyright.clone();
+    gray.encode().ok()
+}
+
+// an RGB profile whose channels all follow the grey tone curve. Equal channels are neutral
+// whatever the primaries, so sRGB's are used.
+fn rgb_profile(gray: &ColorProfile) -> Option<Vec<u8>> {
+    let trc = gray.gray_trc.clone()?;
+    let mut rgb = ColorProfile::new_srgb();
+    rgb.red_trc = Some(trc.clone());
+    rgb.green_trc = Some(trc.clone());
+    rgb.blue_trc = Some(trc);
+    rgb.media_white_point = gray.media_white_point;
+    rgb.cicp = None;
+    rgb.description = gray.description.clone();
+    rgb.copyright = gray.copyright.clone();
+    rgb.encode().ok()
+}
+
+fn color_type(gray: bool, alpha: bool, deep: bool) -> ImageColorType {
+    match (gray, alpha, deep) {
+        (true, false, false) => ImageColorType::L8,
+        (true, true, false) => ImageColorType::La8,
+        (false, false, false) => ImageColorType::Rgb8,
+        (false, true, false) => ImageColorType::Rgba8,
+        (true, false, true) => ImageColorType::L16,
+        (true, true, true) => ImageColorType::La16,
+        (false, false, true) => ImageColorType::Rgb16,
+        (false, true, true) => ImageColorType::Rgba16,
+    }
+}
+
+// adds an ICCP chunk to a WebP file from libwebp, which can't embed one itself. The ICC profile
+// needs the extended format's VP8X header, which libwebp only writes for images with alpha.
+#[cfg(feature = "lossy-webp")]
+fn with_icc_profile(webp: &[u8], icc_profile: &[u8], width: u32, height: u32) -> Vec<u8> {
+    const ICC_FLAG: u8 = 0x20;
+    let chunks = &webp[12..];
+    let mut extended = Vec::with_capacity(webp.len() + icc_profile.len() + 28);
+    extended.extend_from_slice(b"RIFF\0\0\0\0WEBP");
+    let rest = if chunks.starts_with(b"VP8X") {
+        extended.extend_from_slice(&chunks[..18]);
+        extended[20] |= ICC_FLAG;
+        &chunks[18..]
+    } else {
+        extended.extend_from_slice(b"VP8X");
+        extended.extend_from_slice(&10u32.to_le_bytes());
+        extended.extend_from_slice(&[ICC_FLAG, 0, 0, 0]);
+        extended.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        extended.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        chunks
+    };
+    extended.extend_from_slice(b"ICCP");
+    extended.extend_from_slice(&(icc_profile.len() as u32).to_le_bytes());
+    extended.extend_from_slice(icc_profile);
+    if icc_profile.len() % 2 == 1 {
+        extended.push(0);
+    }
+    extended.extend_from_slice(rest);
+    let riff_size = (extended.len() - 8) as u32;
+    extended[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    extended
+}
+
+fn create_file(path: &Path) -> Result<BufWriter<File>, TilerError> {
+    File::create(path)
+        .map(BufWriter::new)
+        .map_err(|e| TilerError::Io {
+            path: path.to_path_buf(),
+            source: e,
+        })
+}
+
+fn flush(mut writer: BufWriter<File>, path: &Path) -> Result<(), TilerError> {
+    writer.flush().map_err(|e| TilerError::Io {
+        path: path.to_path_buf(),
+        source: e,
+    })
+}
+
+fn check_range(name: &str, range: RangeInclusive<u8>, value: u8) -> Result<(), TilerError> {
+    if range.contains(&value) {
+        return Ok(());
+    }
+    Err(TilerError::InvalidParameter(format!(
+        "{} must be between {} and {}, got {}",
+        name,
+        range.start(),
+        range.end(),
+        value
+    )))
+}
+
+fn lossy_webp_disabled() -> TilerError {
+    TilerError::InvalidParameter("Lossy WebP needs the lossy-webp feature".to_string())
+}
+
+fn encode_error(
+    path: &Path,
+    error: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> TilerError {
+    TilerError::Encode {
+        path: path.to_path_buf(),
+        source: ImageError::Encoding(EncodingError::new(
+            ImageFormatHint::Exact(ImageFormat::Jpeg),
+            error,
+        )),
+    }
+}
@@ -0,0 +1,586 @@
+/**
+ * IIIF Presentation API manifests that show tiled images, one canvas per image
+ */
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::image_service::{ImageService, Size, CONTEXT_2, CONTEXT_3, LEVEL0_2};
+use crate::info_json::InfoJSON;
+use crate::output::OutputFormat;
+
+pub const PRESENTATION_CONTEXT_2: &str = "http://iiif.io/api/presentation/2/context.json";
+pub const PRESENTATION_CONTEXT_3: &str = "http://iiif.io/api/presentation/3/context.json";
+
+// thumbnails use the smallest size at least this wide
+const THUMBNAIL_WIDTH: i32 = 200;
+
+/// A v3 language map. Labels generated from file names use the `none` language.
+pub type LanguageMap = BTreeMap<String, Vec<String>>;
+
+/// An image, either painted on a canvas or used as a thumbnail
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ImageResource3 {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    pub format: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<i32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub service: Vec<Value>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Annotation3 {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub annotation_type: String,
+    pub motivation: String,
+    pub body: ImageResource3,
+    pub target: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct AnnotationPage3 {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub page_type: String,
+    pub items: Vec<Annotation3>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Canvas3 {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub canvas_type: String,
+    pub label: LanguageMap,
+    pub width: i32,
+    pub height: i32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub thumbnail: Vec<ImageResource3>,
+    pub items: Vec<AnnotationPage3>,
+}
+
+/// An IIIF Presentation API 3.0 manifest
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Manifest3 {
+    #[serde(rename = "@context")]
+    pub context: String,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub manifest_type: String,
+    pub label: LanguageMap,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub thumbnail: Vec<ImageResource3>,
+    pub items: Vec<Canvas3>,
+}
+
+impl Manifest3 {
+    pub fn from_json(json: &str) -> Result<Manifest3, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    pub fn to_json_pretty(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+/// An image in a v2 manifest, either painted on a canvas or used as a thumbnail
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ImageResource2 {
+    #[serde(rename = "@id")]
+    pub id: String,
+    #[serde(rename = "@type", default, skip_serializing_if = "Option::is_none")]
+    pub resource_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<Value>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Annotation2 {
+    #[serde(rename = "@id")]
+    pub id: String,
+    #[serde(rename = "@type")]
+    pub annotation_type: String,
+    pub motivation: String,
+    pub resource: ImageResource2,
+    pub on: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Canvas2 {
+    #[serde(rename = "@id")]
+    pub id: String,
+    #[serde(rename = "@type")]
+    pub canvas_type: String,
+    pub label: String,
+    pub width: i32,
+    pub height: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<ImageResource2>,
+    pub images: Vec<Annotation2>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Sequence2 {
+    #[serde(rename = "@id")]
+    pub id: String,
+    #[serde(rename = "@type")]
+    pub sequence_type: String,
+    pub canvases: Vec<Canvas2>,
+}
+
+/// An IIIF Presentation API 2.1 manifest
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Manifest2 {
+    #[serde(rename = "@context")]
+    pub context: String,
+    #[serde(rename = "@id")]
+    pub id: String,
+    #[serde(rename = "@type")]
+    pub manifest_type: String,
+    pub label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<ImageResource2>,
+    pub sequences: Vec<Sequence2>,
+}
+
+impl Manifest2 {
+    pub fn from_json(json: &str) -> Result<Manifest2, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    pub fn to_json_pretty(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+/// A manifest or collection listed in a v2 collection
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Reference2 {
+    #[serde(rename = "@id")]
+    pub id: String,
+    #[serde(rename = "@type")]
+    pub reference_type: String,
+    pub label: String,
+}
+
+/// An IIIF Presentation API 2.1 collection
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Collection2 {
+    #[serde(rename = "@context")]
+    pub context: String,
+    #[serde(rename = "@id")]
+    pub id: String,
+    #[serde(rename = "@type")]
+    pub collection_type: String,
+    pub label: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub collections: Vec<Reference2>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub manifests: Vec<Reference2>,
+}
+
+/// A manifest or collection listed in a v3 collection
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Reference3 {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub reference_type: String,
+    pub label: LanguageMap,
+}
+
+/// An IIIF Presentation API 3.0 collection
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Collection3 {
+    #[serde(rename = "@context")]
+    pub context: String,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub collection_type: String,
+    pub label: LanguageMap,
+    pub items: Vec<Reference3>,
+}
+
+impl Collection2 {
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    pub fn to_json_pretty(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+impl Collection3 {
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    pub fn to_json_pretty(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+/// Collects tiled images as canvases and builds the manifest that shows them in order
+#[derive(Debug, PartialEq, Clone)]
+pub struct ManifestBuilder {
+    base: String,
+    label: String,
+    canvases: Vec<(String, ImageService)>,
+}
+
+impl ManifestBuilder {
+    /// `base` is the URI the manifest and its canvases are published under, ending in `/`.
+    pub fn new(base: &str, label: &str) -> ManifestBuilder {
+        ManifestBuilder {
+            base: base.to_owned(),
+            label: label.to_owned(),
+            canvases: Vec::new(),
+        }
+    }
+
+    /// Adds a canvas showing the image the service describes, after any already added.
+    pub fn add_canvas(&mut self, label: &str, service: ImageService) {
+        self.canvases.push((label.to_owned(), service));
+    }
+
+    /// Adds a canvas for an image from its info.json, sized from its image info.
+    pub fn add_image(&mut self, label: &str, info: &InfoJSON) {
+        self.add_canvas(label, info.to_service());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.canvases.is_empty()
+    }
+
+    pub fn id(&self) -> String {
+        format!("{}manifest.json", self.base)
+    }
+
+    /// Builds a Presentation 3 manifest. The first canvas's thumbnail is also the manifest's.
+    pub fn to_manifest3(&self) -> Manifest3 {
+        let items: Vec<Canvas3> = self
+            .canvases
+            .iter()
+            .enumerate()
+            .map(|(index, (label, service))| self.canvas3(index + 1, label, service))
+            .collect();
+        Manifest3 {
+            context: PRESENTATION_CONTEXT_3.to_owned(),
+            id: self.id(),
+            manifest_type: "Manifest".to_owned(),
+            label: language_map(&self.label),
+            thumbnail: items
+                .first()
+                .map(|canvas| canvas.thumbnail.clone())
+                .unwrap_or_default(),
+            items,
+        }
+    }
+
+    fn canvas3(&self, number: usize, label: &str, service: &ImageService) -> Canvas3 {
+        let canvas_id = format!("{}canvas/{}", self.base, number);
+        let (body_url, body_size) = painting_image(service);
+        let body = ImageResource3 {
+            id: body_url,
+            resource_type: "Image".to_owned(),
+            format: mime_type(service),
+            width: Some(body_size.width),
+            height: Some(body_size.height),
+            service: vec![service_reference3(service)],
+        };
+        let thumbnail = thumbnail_size(service.sizes())
+            .map(|size| ImageResource3 {
+                id: size_url(service, size),
+                resource_type: "Image".to_owned(),
+                format: mime_type(service),
+                width: Some(size.width),
+                height: Some(size.height),
+                service: vec![service_reference3(service)],
+            })
+            .into_iter()
+            .collect();
+        Canvas3 {
+            id: canvas_id.clone(),
+            canvas_type: "Canvas".to_owned(),
+            label: language_map(label),
+            width: service.width(),
+            height: service.height(),
+            thumbnail,
+            items: vec![AnnotationPage3 {
+                id: format!("{}page/{}", self.base, number),
+                page_type: "AnnotationPage".to_owned(),
+                items: vec![Annotation3 {
+                    id: format!("{}annotation/{}", self.base, number),
+                    annotation_type: "Annotation".to_owned(),
+                    motivation: "painting".to_owned(),
+                    body,
+                    target: canvas_id,
+                }],
+            }],
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Builds a Presentation 2.1 manifest with a single sequence holding the canvases.
+    pub fn to_manifest2(&self) -> Manifest2 {
+        let canvases: Vec<Canvas2> = self
+            .canvases
+            .iter()
+            .enumerate()
+            .map(|(index, (label, service))| self.canvas2(index + 1, label, service))
+            .collect();
+        Manifest2 {
+            context: PRESENTATION_CONTEXT_2.to_owned(),
+            id: self.id(),
+            manifest_type: "sc:Manifest".to_owned(),
+            label: self.label.clone(),
+            thumbnail: canvases.first().and_then(|canvas| canvas.thumbnail.clone()),
+            sequences: vec![Sequence2 {
+                id: format!("{}sequence/normal", self.base),
+                sequence_type: "sc:Sequence".to_owned(),
+                canvases,
+            }],
+        }
+    }
+
+    fn canvas2(&self, number: usize, label: &str, service: &ImageService) -> Canvas2 {
+        let canvas_id = format!("{}canvas/{}", self.base, number);
+        let (resource_url, resource_size) = painting_image(service);
+        let resource = ImageResource2 {
+            id: resource_url,
+            resource_type: Some("dctypes:Image".to_owned()),
+            format: Some(mime_type(service)),
+            width: Some(resource_size.width),
+            height: Some(resource_size.height),
+            service: Some(service_reference2(service)),
+        };
+        let thumbnail = thumbnail_size(service.sizes()).map(|size| ImageResource2 {
+            id: size_url(service, size),
+            resource_type: None,
+            format: None,
+            width: Some(size.width),
+            height: Some(size.height),
+            service: Some(service_reference2(service)),
+        });
+        Canvas2 {
+            id: canvas_id.clone(),
+            canvas_type: "sc:Canvas".to_owned(),
+            label: label.to_owned(),
+            width: service.width(),
+            height: service.height(),
+            thumbnail,
+            images: vec![Annotation2 {
+                id: format!("{}annotation/{}", self.base, number),
+                annotation_type: "oa:Annotation".to_owned(),
+                motivation: "sc:painting".to_owned(),
+                resource,
+                on: canvas_id,
+            }],
+        }
+    }
+}
+
+/// Lists manifests and other collections, e.g. one per folder of images
+#[derive(Debug, PartialEq, Clone)]
+pub struct CollectionBuilder {
+    base: String,
+    label: String,
+    // (is a collection, id, label)
+    members: Vec<(bool, String, String)>,
+}
+
+impl CollectionBuilder {
+    /// `base` is the URI the collection is published under, ending in `/`.
+    pub fn new(base: &str, label: &str) -> CollectionBuilder {
+        CollectionBuilder {
+            base: base.to_owned(),
+            label: label.to_owned(),
+            members: Vec::new(),
+        }
+    }
+
+    pub fn add_manifest(&mut self, manifest: &ManifestBuilder) {
+        self.members
+            .push((false, manifest.id(), manifest.label().to_owned()));
+    }
+
+    pub fn add_collection(&mut self, collection: &CollectionBuilder) {
+        self.members
+            .push((true, collection.id(), collection.label.clone()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn id(&self) -> String {
+        format!("{}collection.json", self.base)
+    }
+
+    /// Builds a Presentation 2.1 collection, which lists collections and manifests separately.
+    pub fn to_collection2(&self) -> Collection2 {
+        let references = |collections: bool| {
+            self.members
+                .iter()
+                .filter(|(is_collection, _, _)| *is_collection == collections)
+                .map(|(is_collection, id, label)| Reference2 {
+                    id: id.clone(),
+                    reference_type: if *is_collection {
+                        "sc:Collection"
+                    } else {
+                        "sc:Manifest"
+                    }
+                    .to_owned(),
+                    label: label.clone(),
+                })
+                .collect()
+        };
+        Collection2 {
+            context: PRESENTATION_CONTEXT_2.to_owned(),
+            id: self.id(),
+            collection_type: "sc:Collection".to_owned(),
+            label: self.label.clone(),
+            collections: references(true),
+            manifests: references(false),
+        }
+    }
+
+    /// Builds a Presentation 3 collection, which lists its members in the order they were added.
+    pub fn to_collection3(&self) -> Collection3 {
+        Collection3 {
+            context: PRESENTATION_CONTEXT_3.to_owned(),
+            id: self.id(),
+            collection_type: "Collection".to_owned(),
+            label: language_map(&self.label),
+            items: self
+                .members
+                .iter()
+                .map(|(is_collection, id, label)| Reference3 {
+                    id: id.clone(),
+                    reference_type: if *is_collection {
+                        "Collection"
+                    } else {
+                        "Manifest"
+                    }
+                    .to_owned(),
+                    label: language_map(label),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn language_map(label: &str) -> LanguageMap {
+    BTreeMap::from([("none".to_owned(), vec![label.to_owned()])])
+}
+
+// the smallest size at least THUMBNAIL_WIDTH wide, or the largest if they're all narrower
+fn thumbnail_size(sizes: &[Size]) -> Option<&Size> {
+    sizes
+        .iter()
+        .filter(|size| size.width >= THUMBNAIL_WIDTH)
+        .min_by_key(|size| size.width)
+        .or_else(|| sizes.iter().max_by_key(|size| size.width))
+}
+
+fn mime_type(service: &ImageService) -> String {
+    OutputFormat::from_str(service.preferred_format())
+        .map_or("image/jpeg", |format| format.mime_type())
+        .to_owned()
+}
+
+// the largest image the tiler wrote along with its size. That's the full image, which v3 calls
+// `max` and v2 calls `full`, unless the info.json only lists smaller sizes.
+fn painting_image(service: &ImageService) -> (String, Size) {
+    let full = Size {
+        width: service.width(),
+        height: service.height(),
+    };
+    match service.sizes().iter().max_by_key(|size| size.width) {
+        Some(largest) if *largest != full => (size_url(service, largest), largest.clone()),
+        _ => {
+            let size = match service {
+                ImageService::V3(_) => "max",
+                ImageService::V2(_) => "full",
+            };
+            let url = format!(
+                "{}/full/{}/0/default.{}",
+                service.id(),
+                size,
+                service.preferred_format()
+            );
+            (url, full)
+        }
+    }
+}
+
+// a size in the version's canonical form, `w,h` in v3 and `w,` in v2
+fn size_url(service: &ImageService, size: &Size) -> String {
+    let size_str = match service {
+        ImageService::V3(_) => format!("{},{}", size.width, size.height),
+        ImageService::V2(_) => format!("{},", size.width),
+    };
+    format!(
+        "{}/full/{}/0/default.{}",
+        service.id(),
+        size_str,
+        service.preferred_format()
+    )
+}
+
+// a reference to the image service rather than the whole info.json
+fn service_reference3(service: &ImageService) -> Value {
+    match service {
+        ImageService::V3(service) => json!({
+            "id": service.id,
+            "type": "ImageService3",
+            "profile": service.profile,
+        }),
+        ImageService::V2(service) => json!({
+            "@id": service.id,
+            "@type": "ImageService2",
+            "profile": LEVEL0_2,
+        }),
+    }
+}
+
+// the image service as a v2 manifest embeds it, with its context
+fn service_reference2(service: &ImageService) -> Value {
+    match service {
+        ImageService::V3(service) => json!({
+            "@context": CONTEXT_3,
+            "id": service.id,
+            "type": "ImageService3",
+            "profile": service.profile,
+        }),
+        ImageService::V2(service) => json!({
+            "@context": CONTEXT_2,
+            "@id": service.id,
+            "profile": LEVEL0_2,
+        }),
+    }
+}
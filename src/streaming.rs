@@ -0,0 +1,387 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use image::error::{DecodingError, ImageFormatHint};
+use image::{ImageError, ImageFormat, ImageReader, RgbImage};
+use tiff::decoder::{Decoder as TiffDecoder, DecodingResult};
+use tiff::ColorType as TiffColorType;
+
+use crate::error::TilerError;
+
+/// Bytes per pixel of the rows handed out by a [`BandReader`], which are always packed RGB8.
+pub const BYTES_PER_PIXEL: usize = 3;
+
+/**
+ * Reads an image from disk a band of rows at a time rather than decoding all of it into memory.
+ */
+pub trait BandReader {
+    fn dimensions(&self) -> (u32, u32);
+
+    /// Reads up to `rows` more rows as packed RGB8. Fewer rows are returned at the end of the
+    /// image and none once every row has been read.
+    fn read_rows(&mut self, rows: u32) -> Result<Vec<u8>, TilerError>;
+}
+
+/// Whether the image at `path` can be read a band at a time. PNG (non-interlaced) and TIFF are supported.
+pub fn can_stream(path: &Path) -> bool {
+    open_band_reader(path).is_ok()
+}
+
+/// Opens a band reader for a PNG or TIFF image, working out the format from the file's contents.
+pub fn open_band_reader(path: &Path) -> Result<Box<dyn BandReader>, TilerError> {
+    let format = ImageReader::new(BufReader::new(open_file(path)?))
+        .with_guessed_format()
+        .map_err(|e| TilerError::Io {
+            path: path.to_path_buf(),
+            source: e,
+        })?
+        .format();
+
+    match format {
+        Some(ImageFormat::Png) => Ok(Box::new(PngBandReader::open(path)?)),
+        Some(ImageFormat::Tiff) => Ok(Box::new(TiffBandReader::open(path)?)),
+        _ => Err(TilerError::InvalidParameter(format!(
+            "Only PNG and TIFF images can be streamed: {}",
+            path.display()
+        ))),
+    }
+}
+
+struct PngBandReader {
+    path: PathBuf,
+    reader: png::Reader<BufReader<File>>,
+    width: u32,
+    height: u32,
+}
+
+impl PngBandReader {
+    fn open(path: &Path) -> Result<Self, TilerError> {
+        let mut decoder = png::Decoder::new(BufReader::new(open_file(path)?));
+        // expand palettes and reduce 16 bit samples so every row comes out as 8 bit
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let reader = decoder
+            .read_info()
+            .map_err(|e| decode_error(path, ImageFormat::Png, e))?;
+
+        let info = reader.info();
+        if info.interlaced {
+            return Err(TilerError::InvalidParameter(format!(
+                "Interlaced PNGs can't be streamed: {}",
+                path.display()
+            )));
+        }
+        let (width, height) = (info.width, info.height);
+
+        Ok(PngBandReader {
+            path: path.to_path_buf(),
+            reader,
+            width,
+            height,
+        })
+    }
+}
+
+impl BandReader for PngBandReader {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn read_rows(&mut self, rows: u32) -> Result<Vec<u8>, TilerError> {
+        let channels = self.reader.output_color_type().0.samples();
+        let mut band = Vec::with_capacity(rows as usize * self.width as usize * BYTES_PER_PIXEL);
+        for _ in 0..rows {
+            let row = self
+                .reader
+                .next_row()
+                .map_err(|e| decode_error(&self.path, ImageFormat::Png, e))?;
+            match row {
+                Some(row) => push_rgb8(&mut band, row.data(), channels),
+                None => break,
+            }
+        }
+        Ok(band)
+    }
+}
+
+struct TiffBandReader {
+    path: PathBuf,
+    decoder: TiffDecoder<BufReader<File>>,
+    width: u32,
+    height: u32,
+    channels: usize,
+    // strips are one chunk across, tiled TIFFs have a row of tiles making up each band of chunks
+    chunk_width: u32,
+    chunk_height: u32,
+    chunks_across: u32,
+    next_chunk_row: u32,
+    // decoded rows that haven't been handed out yet
+    pending: Vec<u8>,
+}
+
+impl TiffBandReader {
+    fn open(path: &Path) -> Result<Self, TilerError> {
+        let tiff_error = |e| decode_error(path, ImageFormat::Tiff, e);
+        let mut decoder = TiffDecoder::new(BufReader::new(open_file(path)?)).map_err(tiff_error)?;
+        let (width, height) = decoder.dimensions().map_err(tiff_error)?;
+        let channels = match decoder.colortype().map_err(tiff_error)? {
+            TiffColorType::Gray(8 | 16) => 1,
+            TiffColorType::GrayA(8 | 16) => 2,
+            TiffColorType::RGB(8 | 16) => 3,
+            TiffColorType::RGBA(8 | 16) => 4,
+            other => {
+                return Err(TilerError::InvalidParameter(format!(
+                    "Can't stream TIFF with colour type {:?}: {}",
+                    other,
+                    path.display()
+                )))
+            }
+        };
+        let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+
+        Ok(TiffBandReader {
+            path: path.to_path_buf(),
+            decoder,
+            width,
+            height,
+            channels,
+            chunk_width,
+            chunk_height,
+            chunks_across: width.div_ceil(chunk_width),
+            next_chunk_row: 0,
+            pending: Vec::new(),
+        })
+    }
+
+    // decodes the next row of chunks onto the end of the pending rows
+    fn read_chunk_row(&mut self) -> Result<(), TilerError> {
+        let row_bytes = self.width as usize * BYTES_PER_PIXEL;
+        let first_chunk = self.next_chunk_row * self.chunks_across;
+        let rows = self.decoder.chunk_data_dimensions(first_chunk).1 as usize;
+        let mut band = vec![0; rows * row_bytes];
+
+        for across in 0..self.chunks_across {
+            let chunk_index = first_chunk + across;
+            let (data_width, data_height) = self.decoder.chunk_data_dimensions(chunk_index);
+            let chunk = self
+                .decoder
+                .read_chunk(chunk_index)
+                .map_err(|e| decode_error(&self.path, ImageFormat::Tiff, e))?;
+
+            let mut rgb = Vec::with_capacity((data_width * data_height) as usize * BYTES_PER_PIXEL);
+            match chunk {
+                DecodingResult::U8(samples) => push_rgb8(&mut rgb, &samples, self.channels),
+                DecodingResult::U16(samples) => {
+                    let samples: Vec<u8> =
+                        samples.iter().map(|sample| (sample >> 8) as u8).collect();
+                    push_rgb8(&mut rgb, &samples, self.channels)
+                }
+                _ => {
+                    return Err(TilerError::InvalidParameter(format!(
+                        "Can't stream TIFF with this sample format: {}",
+                        self.path.display()
+                    )))
+                }
+            }
+
+            // copy the chunk's rows into place across the band
+            let chunk_row_bytes = data_width as usize * BYTES_PER_PIXEL;
+            let x_offset = (across * self.chunk_width) as usize * BYTES_PER_PIXEL;
+            for (y, chunk_row) in rgb.chunks_exact(chunk_row_bytes).enumerate() {
+                let start = y * row_bytes + x_offset;
+                band[start..start + chunk_row_bytes].copy_from_slice(chunk_row);
+            }
+        }
+
+        self.pending.extend_from_slice(&band);
+        self.next_chunk_row += 1;
+        Ok(())
+    }
+}
+
+impl BandReader for TiffBandReader {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn read_rows(&mut self, rows: u32) -> Result<Vec<u8>, TilerError> {
+        let row_bytes = self.width as usize * BYTES_PER_PIXEL;
+        let wanted = rows as usize * row_bytes;
+        while self.pending.len() < wanted && self.next_chunk_row * self.chunk_height < self.height {
+            self.read_chunk_row()?;
+        }
+
+        let rest = self.pending.split_off(wanted.min(self.pending.len()));
+        Ok(std::mem::replace(&mut self.pending, rest))
+    }
+}
+
+/**
+ * Holds one zoom level of a streamed pyramid. Rows arrive from the level above (or the source
+ * image) a few at a time and leave either as bands ready to be cut into tiles or as rows
+ * downsampled for the level below, so only a band's worth of rows is ever held.
+ */
+pub(crate) struct LevelBuffer {
+    pub scale: i32,
+    pub width: u32,
+    pub height: u32,
+    tile_height: u32,
+    band: Vec<u8>,
+    band_start: u32,
+    // an odd row waiting for the row below it before it can be downsampled
+    carry: Option<Vec<u8>>,
+    rows_received: u32,
+    // the whole level, only kept when it's written out as one of the sizes
+    full: Option<Vec<u8>>,
+}
+
+// A band of rows from a level, starting at row `start` of the level
+pub(crate) struct Band {
+    pub start: u32,
+    pub image: RgbImage,
+}
+
+impl LevelBuffer {
+    pub fn new(scale: i32, width: u32, height: u32, tile_height: u32) -> Self {
+        LevelBuffer {
+            scale,
+            width,
+            height,
+            tile_height,
+            band: Vec::new(),
+            band_start: 0,
+            carry: None,
+            rows_received: 0,
+            full: None,
+        }
+    }
+
+    fn row_bytes(&self) -> usize {
+        self.width as usize * BYTES_PER_PIXEL
+    }
+
+    // the most this level holds at once while streaming: a band plus the rows arriving for it
+    pub fn working_bytes(&self) -> usize {
+        (2 * self.tile_height as usize + 1) * self.row_bytes()
+    }
+
+    pub fn full_bytes(&self) -> usize {
+        self.height as usize * self.row_bytes()
+    }
+
+    /// Keep every row of this level so it can be written out as a size once streaming finishes.
+    pub fn keep_full(&mut self) {
+        self.full = Some(Vec::with_capacity(self.full_bytes()));
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.rows_received >= self.height
+    }
+
+    /// Adds rows to the level, returning any bands that are ready to be tiled and the rows
+    /// downsampled for the next level.
+    pub fn push_rows(&mut self, rows: &[u8]) -> (Vec<Band>, Vec<u8>) {
+        let row_bytes = self.row_bytes();
+        let row_count = rows.len() / row_bytes;
+        self.rows_received += row_count as u32;
+
+        if let Some(full) = &mut self.full {
+            full.extend_from_slice(rows);
+        }
+
+        // downsample pairs of rows, carrying any odd row over to the next push
+        let mut downsampled = Vec::new();
+        let mut next_row = 0;
+        if row_count > 0 {
+            if let Some(carry) = self.carry.take() {
+                downsampled.extend(downsample_rows(&carry, &rows[..row_bytes], self.width));
+                next_row = 1;
+            }
+        }
+        while next_row + 1 < row_count {
+            let pair = &rows[next_row * row_bytes..(next_row + 2) * row_bytes];
+            let (top, bottom) = pair.split_at(row_bytes);
+            downsampled.extend(downsample_rows(top, bottom, self.width));
+            next_row += 2;
+        }
+        if next_row < row_count {
+            self.carry = Some(rows[next_row * row_bytes..].to_vec());
+        }
+        if self.is_complete() {
+            // the last row of an odd height level is averaged with itself
+            if let Some(carry) = self.carry.take() {
+                downsampled.extend(downsample_rows(&carry, &carry, self.width));
+            }
+        }
+
+        self.band.extend_from_slice(rows);
+        let mut bands = Vec::new();
+        let band_bytes = self.tile_height as usize * row_bytes;
+        while self.band.len() >= band_bytes || (self.is_complete() && !self.band.is_empty()) {
+            let rest = self.band.split_off(band_bytes.min(self.band.len()));
+            let band = std::mem::replace(&mut self.band, rest);
+            let band_rows = (band.len() / row_bytes) as u32;
+            bands.push(Band {
+                start: self.band_start,
+                image: RgbImage::from_raw(self.width, band_rows, band)
+                    .expect("band holds whole rows"),
+            });
+            self.band_start += band_rows;
+        }
+
+        (bands, downsampled)
+    }
+
+    /// The whole level, if it was kept with [`LevelBuffer::keep_full`].
+    pub fn take_full(&mut self) -> Option<RgbImage> {
+        self.full
+            .take()
+            .and_then(|full| RgbImage::from_raw(self.width, self.height, full))
+    }
+}
+
+// averages each 2x2 block of pixels across two rows, giving a row half as wide (rounded up)
+fn downsample_rows(top: &[u8], bottom: &[u8], width: u32) -> Vec<u8> {
+    let width = width as usize;
+    let mut row = Vec::with_capacity(width.div_ceil(2) * BYTES_PER_PIXEL);
+    for x in (0..width).step_by(2) {
+        // the last column of an odd width row is averaged with itself
+        let right = (x + 1).min(width - 1);
+        for channel in 0..BYTES_PER_PIXEL {
+            let sum = top[x * BYTES_PER_PIXEL + channel] as u32
+                + top[right * BYTES_PER_PIXEL + channel] as u32
+                + bottom[x * BYTES_PER_PIXEL + channel] as u32
+                + bottom[right * BYTES_PER_PIXEL + channel] as u32;
+            row.push(((sum + 2) / 4) as u8);
+        }
+    }
+    row
+}
+
+// converts interleaved gray, gray + alpha, RGB or RGBA samples to RGB8, dropping any alpha
+fn push_rgb8(rgb: &mut Vec<u8>, samples: &[u8], channels: usize) {
+    for pixel in samples.chunks_exact(channels) {
+        match channels {
+            1 | 2 => rgb.extend_from_slice(&[pixel[0], pixel[0], pixel[0]]),
+            _ => rgb.extend_from_slice(&pixel[..3]),
+        }
+    }
+}
+
+fn open_file(path: &Path) -> Result<File, TilerError> {
+    File::open(path).map_err(|e| TilerError::Io {
+        path: path.to_path_buf(),
+        source: e,
+    })
+}
+
+pub(crate) fn decode_error(
+    path: &Path,
+    format: ImageFormat,
+    error: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> TilerError {
+    TilerError::Decode {
+        path: Some(path.to_path_buf()),
+        source: ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(format), error)),
+    }
+}
@@ -1,187 +1,758 @@
-use std::fs::create_dir_all;
+use std::borrow::Cow;
+use std::fs::{self, create_dir_all};
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use crate::error::TilerError;
 use crate::image_info::ImageInfo;
-use crate::info_json::{IIIFVersion, InfoJSON};
-use anyhow::{Error, Result};
+use crate::info_json::{IIIFVersion, InfoJSON, Properties};
+use crate::output::{
+    fits_formats, level0_formats, Bitonal, EncoderSettings, IccProfiles, OutputFormat, Quality,
+    Rotation,
+};
+use crate::streaming::{open_band_reader, LevelBuffer};
+use image::imageops::FilterType;
 use image::DynamicImage;
-use log::info;
+use log::{error, info, warn};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+/// How the scaled down sizes and tiles are produced
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum TilingMode {
+    /// Every tile and size is resized straight from the full resolution image.
+    #[default]
+    FullResolution,
+    /// Each zoom level is downsampled once from the level above it and tiles are cut out of the level.
+    /// Much faster on deep pyramids.
+    Pyramid,
+}
+
+/// Memory budget for streaming when none is set, 1 GiB
+pub const DEFAULT_MEMORY_BUDGET: usize = 1 << 30;
+
+// A tile's region in the full sized image along with the size it's scaled down to
+struct TileRegion {
+    scale: i32,
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+    scaled_width: i32,
+    scaled_height: i32,
+}
 
 pub struct Tiler<'a> {
     image: &'a ImageInfo<'a>,
-    version: &'a IIIFVersion,
+    versions: Vec<IIIFVersion>,
+    fail_fast: bool,
+    threads: usize,
+    mode: TilingMode,
+    filter: FilterType,
+    memory_budget: usize,
+    formats: Vec<OutputFormat>,
+    encoder: EncoderSettings,
+    qualities: Vec<Quality>,
+    bitonal: Bitonal,
+    rotations: Vec<Rotation>,
+    rotate_tiles: bool,
+    pretty: bool,
+    properties: Properties,
+    icc_profiles: IccProfiles,
 }
 
 impl<'a> Tiler<'a> {
     pub fn new(image: &'a ImageInfo, version: &'a IIIFVersion) -> Tiler<'a> {
-        Tiler { image, version }
+        Tiler {
+            image,
+            versions: vec![*version],
+            fail_fast: false,
+            threads: 0,
+            mode: TilingMode::FullResolution,
+            filter: FilterType::Triangle,
+            memory_budget: DEFAULT_MEMORY_BUDGET,
+            formats: vec![OutputFormat::Jpg],
+            encoder: EncoderSettings::default(),
+            qualities: Vec::new(),
+            bitonal: Bitonal::default(),
+            rotations: Vec::new(),
+            rotate_tiles: false,
+            pretty: false,
+            properties: Properties::default(),
+            icc_profiles: IccProfiles::default(),
+        }
+    }
+
+    /// Writes the pyramid for each of these versions from the one decode. With more than one version
+    /// each goes in its own service directory, e.g. `v2/<id>` and `v3/<id>`, and files are written
+    /// once then hard linked into the other layouts.
+    pub fn set_versions(&mut self, versions: Vec<IIIFVersion>) {
+        self.versions = versions;
+    }
+
+    /// The directory a version's tiles and info.json are written to
+    pub fn service_dir(&self, output_dir: &str, version: &IIIFVersion) -> PathBuf {
+        if self.versions.len() > 1 {
+            PathBuf::from(output_dir)
+                .join(version.dir_name())
+                .join(self.image.id())
+        } else {
+            PathBuf::from(output_dir).join(self.image.id())
+        }
+    }
+
+    // every version's service directory, the first being where files are encoded
+    fn service_dirs(&self, output_dir: &str) -> Vec<(IIIFVersion, PathBuf)> {
+        self.versions
+            .iter()
+            .map(|version| (*version, self.service_dir(output_dir, version)))
+            .collect()
+    }
+
+    /// Sets the formats every tile and size is written in. The first is the preferred format, and
+    /// jpg is written after them when they don't include it.
+    pub fn set_formats(&mut self, formats: Vec<OutputFormat>) {
+        self.formats = level0_formats(formats);
+    }
+
+    /// Sets the qualities written alongside `default` for every tile and size.
+    pub fn set_qualities(&mut self, qualities: Vec<Quality>) {
+        self.qualities = qualities;
+    }
+
+    /// Sets how the bitonal quality is reduced to black and white.
+    pub fn set_bitonal(&mut self, bitonal: Bitonal) {
+        self.bitonal = bitonal;
+    }
+
+    /// Sets the rotations written alongside `0` for the full image and sizes.
+    pub fn set_rotations(&mut self, rotations: Vec<Rotation>) {
+        self.rotations = rotations;
+    }
+
+    /// Also write the rotations for every tile, not just the full image and sizes.
+    pub fn set_rotate_tiles(&mut self, rotate_tiles: bool) {
+        self.rotate_tiles = rotate_tiles;
+    }
+
+    /// Sets the descriptive properties written into the info.json, e.g. `rights`.
+    pub fn set_properties(&mut self, properties: Properties) {
+        self.properties = properties;
+    }
+
+    /// Indent the info.json returned by [`Tiler::create`] rather than writing it on one line.
+    pub fn set_pretty(&mut self, pretty: bool) {
+        self.pretty = pretty;
+    }
+
+    /// Embeds this ICC profile, e.g. the source's [`crate::iiif_image::IIIFImage::icc_profile`], in
+    /// every JPEG, PNG and WebP written. Other formats are written without it. Files whose pixels
+    /// are grey while the profile is for RGB, or the other way round, get a profile of their own
+    /// colour space with the same tone curve instead.
+    pub fn set_icc_profile(&mut self, icc_profile: Option<Vec<u8>>) {
+        self.icc_profiles = IccProfiles::new(icc_profile);
+    }
+
+    /// Sets the JPEG, PNG and WebP encoder settings tiles and sizes are written with.
+    pub fn set_encoder_settings(&mut self, encoder: EncoderSettings) {
+        self.encoder = encoder;
+    }
+
+    /// Sets roughly how many bytes of pixels [`Tiler::generate_tiles_streaming`] may hold at once.
+    pub fn set_memory_budget(&mut self, bytes: usize) {
+        self.memory_budget = bytes;
+    }
+
+    pub fn set_mode(&mut self, mode: TilingMode) {
+        self.mode = mode;
+    }
+
+    /// Sets the resampling filter used to downsample each level in [`TilingMode::Pyramid`].
+    pub fn set_filter(&mut self, filter: FilterType) {
+        self.filter = filter;
+    }
+
+    /// Sets how many threads encode tiles in parallel. `0` (the default) uses one thread per CPU core.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads;
+    }
+
+    /// Stop at the first tile that fails to write rather than carrying on and reporting every failure.
+    pub fn set_fail_fast(&mut self, fail_fast: bool) {
+        self.fail_fast = fail_fast;
     }
 
     pub fn get_output_dir(&self, p_image_dir: &str) -> String {
         format!("{}/{}", p_image_dir, self.image.id())
     }
 
-    pub fn generate_tiles(&self, image_dir: &str) -> Result<(), Error> {
-        self._generate_tiles(image_dir, &self.image.id())?;
+    pub fn generate_tiles(&self, image_dir: &str) -> Result<(), TilerError> {
+        self._generate_tiles(&self.service_dirs(image_dir))?;
         Ok(())
     }
 
-    fn _generate_tiles(&self, image_dir: &str, filename: &str) -> Result<(), Error> {
-        let img_dir = format!("{}/{}", image_dir, filename);
+    fn _generate_tiles(&self, img_dirs: &[(IIIFVersion, PathBuf)]) -> Result<(), TilerError> {
         info!("Using {}", self.image);
-        info!("Creating full scaled images...");
-        self._generate_sizes(&img_dir)?;
+        self.check_settings()?;
+        if !self.image.has_image() {
+            return Err(TilerError::InvalidParameter(format!(
+                "{} hasn't been decoded, use generate_tiles_streaming to tile it from disk",
+                self.image.id()
+            )));
+        }
+        let source = self.image.image();
+        let pool = self.build_pool()?;
+        let sizes = self.encodable_sizes();
+
+        let mut failures = Vec::new();
+        match self.mode {
+            TilingMode::FullResolution => {
+                info!("Creating full scaled images...");
+                let size_results = pool.install(|| self._generate_sizes(source, img_dirs, &sizes));
+                self.record_failures(&mut failures, size_results)?;
+                info!("Creating tiles...");
+                let tile_results = pool.install(|| self._generate_scale_tiles(source, img_dirs));
+                self.record_failures(&mut failures, tile_results)?;
+            }
+            TilingMode::Pyramid => {
+                info!("Downsampling pyramid levels...");
+                let levels = self.build_levels(source);
+                info!("Creating full scaled images...");
+                let size_levels: Vec<&(i32, Cow<DynamicImage>)> = levels
+                    .iter()
+                    .filter(|(_, level)| {
+                        sizes.contains(&(level.width() as i32, level.height() as i32))
+                    })
+                    .collect();
+                let size_results = pool.install(|| {
+                    self.write_all(&size_levels, |(_, level)| {
+                        let size = (level.width() as i32, level.height() as i32);
+                        self.write_size(img_dirs, size, level)
+                    })
+                });
+                self.record_failures(&mut failures, size_results)?;
+                info!("Creating tiles...");
+                let tile_results = pool.install(|| self._generate_level_tiles(img_dirs, &levels));
+                self.record_failures(&mut failures, tile_results)?;
+            }
+        }
+        if !failures.is_empty() {
+            return Err(TilerError::Tiles(failures));
+        }
+        Ok(())
+    }
+
+    /// Tiles a PNG or TIFF straight from disk a band of rows at a time, so the whole image is never
+    /// decoded into memory. Each zoom level is downsampled from the one above with a 2x2 box filter.
+    ///
+    /// Peak memory is kept within the memory budget, which must hold a band of every level. The full
+    /// sized image is written when it fits in the rest of the budget, then the other sizes that
+    /// fit in what's left, smallest first. The sizes written are returned so the info.json only
+    /// lists those, and without the full sized image there's no `full/max` (`full/full` in v2.1).
+    ///
+    /// Rows are tiled as they're stored, so any EXIF orientation isn't applied.
+    pub fn generate_tiles_streaming(
+        &self,
+        source: &Path,
+        image_dir: &str,
+    ) -> Result<Vec<(i32, i32)>, TilerError> {
+        let img_dirs = self.service_dirs(image_dir);
+        info!("Streaming {} using {}", source.display(), self.image);
+        self.check_settings()?;
+        let mut reader = open_band_reader(source)?;
+        let (width, height) = reader.dimensions();
+        if (width as i32, height as i32) != (self.image.get_width(), self.image.get_height()) {
+            return Err(TilerError::InvalidParameter(format!(
+                "{} is {}x{} but the image info is for {}x{}",
+                source.display(),
+                width,
+                height,
+                self.image.get_width(),
+                self.image.get_height()
+            )));
+        }
+        let pool = self.build_pool()?;
+
+        let tile_height = self.image.get_tile_height() as u32;
+        let mut scale_factors = self.image.get_scale_factors();
+        scale_factors.sort();
+        let mut levels: Vec<LevelBuffer> = scale_factors
+            .iter()
+            .map(|&scale| {
+                LevelBuffer::new(
+                    scale,
+                    (width as f64 / scale as f64).ceil() as u32,
+                    (height as f64 / scale as f64).ceil() as u32,
+                    tile_height,
+                )
+            })
+            .collect();
+
+        let working_bytes: usize = levels.iter().map(|level| level.working_bytes()).sum();
+        if working_bytes > self.memory_budget {
+            return Err(TilerError::InvalidParameter(format!(
+                "Streaming {} needs a memory budget of at least {} bytes",
+                source.display(),
+                working_bytes
+            )));
+        }
+        let sizes = self.encodable_sizes();
+        let is_size =
+            |level: &LevelBuffer| sizes.contains(&(level.width as i32, level.height as i32));
+        // the full sized image is kept first, as level 0 clients ask for full/max, when it fits
+        let mut remaining = self.memory_budget - working_bytes;
+        if is_size(&levels[0]) {
+            if levels[0].full_bytes() > remaining {
+                warn!(
+                    "Leaving out the full sized image, {}x{} doesn't fit in the memory budget",
+                    width, height
+                );
+            } else {
+                remaining -= levels[0].full_bytes();
+                levels[0].keep_full();
+            }
+        }
+        // then whole levels for the other sizes, smallest first, for as long as they fit
+        for level in levels[1..].iter_mut().rev() {
+            if !is_size(level) {
+                continue;
+            }
+            if level.full_bytes() > remaining {
+                info!(
+                    "Skipping sizes of {}x{} and larger, they don't fit in the memory budget",
+                    level.width, level.height
+                );
+                break;
+            }
+            remaining -= level.full_bytes();
+            level.keep_full();
+        }
+        let tile_regions: Vec<Vec<TileRegion>> = scale_factors
+            .iter()
+            .map(|&scale| self.tile_regions(scale))
+            .collect();
+
+        let mut failures = Vec::new();
         info!("Creating tiles...");
-        self._generate_scale_tiles(&img_dir)?;
+        while !levels[0].is_complete() {
+            let mut rows = reader.read_rows(tile_height)?;
+            if rows.is_empty() {
+                return Err(TilerError::InvalidParameter(format!(
+                    "{} ended before all {} rows were read",
+                    source.display(),
+                    height
+                )));
+            }
+            // pass the rows down the pyramid, tiling each band as it fills up
+            for (level, regions) in levels.iter_mut().zip(tile_regions.iter()) {
+                let (bands, downsampled) = level.push_rows(&rows);
+                for band in bands {
+                    let band_image = DynamicImage::ImageRgb8(band.image);
+                    let band_start = band.start as i32 * level.scale;
+                    let tiles: Vec<&TileRegion> =
+                        regions.iter().filter(|tile| tile.y == band_start).collect();
+                    let tile_results = pool.install(|| {
+                        self.write_all(&tiles, |tile| {
+                            let tile_image = band_image.crop_imm(
+                                (tile.x / tile.scale) as u32,
+                                0,
+                                tile.scaled_width as u32,
+                                tile.scaled_height as u32,
+                            );
+                            self.save_variants(
+                                &tile_image,
+                                &self.tile_dirs(&img_dirs, tile),
+                                self.rotate_tiles,
+                            )
+                        })
+                    });
+                    self.record_failures(&mut failures, tile_results)?;
+                }
+                rows = downsampled;
+            }
+        }
+
+        info!("Creating full scaled images...");
+        let sizes: Vec<DynamicImage> = levels
+            .iter_mut()
+            .rev()
+            .filter_map(|level| level.take_full())
+            .map(DynamicImage::ImageRgb8)
+            .collect();
+        let size_results = pool.install(|| {
+            self.write_all(&sizes, |size| {
+                self.write_size(&img_dirs, (size.width() as i32, size.height() as i32), size)
+            })
+        });
+        self.record_failures(&mut failures, size_results)?;
+
+        if !failures.is_empty() {
+            return Err(TilerError::Tiles(failures));
+        }
+        Ok(sizes
+            .iter()
+            .map(|size| (size.width() as i32, size.height() as i32))
+            .collect())
+    }
+
+    fn check_settings(&self) -> Result<(), TilerError> {
+        if self.versions.is_empty() {
+            return Err(TilerError::InvalidParameter(
+                "At least one IIIF version is needed".to_string(),
+            ));
+        }
+        if self.formats.is_empty() {
+            return Err(TilerError::InvalidParameter(
+                "At least one output format is needed".to_string(),
+            ));
+        }
+        let (tile_width, tile_height) = (self.image.get_tile_width(), self.image.get_tile_height());
+        if tile_width <= 0 || tile_height <= 0 {
+            return Err(TilerError::InvalidParameter(format!(
+                "Tiles must be at least 1 pixel wide and high, got {}x{}",
+                tile_width, tile_height
+            )));
+        }
+        if self.image.get_zoom_level() < 0 {
+            return Err(TilerError::InvalidParameter(format!(
+                "Zoom levels can't be negative, got {}",
+                self.image.get_zoom_level()
+            )));
+        }
+        self.encoder.check()
+    }
+
+    fn build_pool(&self) -> Result<ThreadPool, TilerError> {
+        ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()
+            .map_err(|e| {
+                TilerError::InvalidParameter(format!("Failed to start tiling threads: {}", e))
+            })
+    }
+
+    // records failed writes in tile order, or hands back the first one when failing fast
+    fn record_failures(
+        &self,
+        failures: &mut Vec<TilerError>,
+        results: Vec<Result<(), TilerError>>,
+    ) -> Result<(), TilerError> {
+        for result in results {
+            if let Err(e) = result {
+                if self.fail_fast {
+                    return Err(e);
+                }
+                error!("{}", e);
+                failures.push(e);
+            }
+        }
         Ok(())
     }
 
-    fn _generate_sizes(&self, image_dir: &str) -> Result<(), Error> {
-        for size in self.image.get_sizes() {
-            let size_str = format!("{},", size.0);
-            let scaled_image = self.image.get_image().get_image().resize(
-                size.0 as u32,
-                size.1 as u32,
-                image::imageops::FilterType::Nearest,
+    // runs a write for every item on the current thread pool, skipping the rest once one fails
+    // if we're failing fast. Results come back in the same order as the items.
+    fn write_all<T: Sync>(
+        &self,
+        items: &[T],
+        write: impl Fn(&T) -> Result<(), TilerError> + Sync,
+    ) -> Vec<Result<(), TilerError>> {
+        let abort = AtomicBool::new(false);
+        items
+            .par_iter()
+            .map(|item| {
+                if abort.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+                let result = write(item);
+                if result.is_err() && self.fail_fast {
+                    abort.store(true, Ordering::Relaxed);
+                }
+                result
+            })
+            .collect()
+    }
+
+    // the image info's sizes that every output format can hold, warning about the rest
+    fn encodable_sizes(&self) -> Vec<(i32, i32)> {
+        let (sizes, too_large): (Vec<_>, Vec<_>) = self
+            .image
+            .get_sizes()
+            .into_iter()
+            .partition(|size| fits_formats(&self.formats, *size));
+        for (width, height) in too_large {
+            warn!(
+                "Leaving out the {}x{} size, it's larger than the output formats can hold",
+                width, height
             );
+        }
+        sizes
+    }
 
-            let output_path = PathBuf::from(image_dir)
-                .join("full")
-                .join(size_str)
-                .join("0")
-                .join("default.jpg");
-            save_image(&scaled_image, &output_path)?;
-            if size.0 == self.image.get_width() && size.1 == self.image.get_height() {
-                let max_full_str = if *self.version == IIIFVersion::VERSION3 {
+    fn _generate_sizes(
+        &self,
+        source: &DynamicImage,
+        image_dirs: &[(IIIFVersion, PathBuf)],
+        sizes: &[(i32, i32)],
+    ) -> Vec<Result<(), TilerError>> {
+        self.write_all(sizes, |size| {
+            let scaled_image = source.resize(size.0 as u32, size.1 as u32, FilterType::Nearest);
+            self.write_size(image_dirs, *size, &scaled_image)
+        })
+    }
+
+    fn write_size(
+        &self,
+        image_dirs: &[(IIIFVersion, PathBuf)],
+        size: (i32, i32),
+        scaled_image: &DynamicImage,
+    ) -> Result<(), TilerError> {
+        let is_full = size.0 == self.image.get_width() && size.1 == self.image.get_height();
+        let mut size_dirs = Vec::new();
+        for (version, image_dir) in image_dirs {
+            // the canonical size is `w,h` in v3 and `w,` in v2.1
+            let size_str = if *version == IIIFVersion::VERSION3 {
+                format!("{},{}", size.0, size.1)
+            } else {
+                format!("{},", size.0)
+            };
+            size_dirs.push(image_dir.join("full").join(size_str));
+            if is_full {
+                let max_full_str = if *version == IIIFVersion::VERSION3 {
                     "max"
                 } else {
                     "full"
                 };
-                let output_path = PathBuf::from(image_dir)
-                    .join("full")
-                    .join(max_full_str)
-                    .join("0")
-                    .join("default.jpg");
-                save_image(&scaled_image, &output_path)?;
+                size_dirs.push(image_dir.join("full").join(max_full_str));
             }
         }
-        Ok(())
+        self.save_variants(scaled_image, &size_dirs, true)
     }
 
-    fn _generate_scale_tiles(&self, p_image_dir: &str) -> Result<(), Error> {
-        for scale in self.image.get_scale_factors() {
-            //height in units of scale rather than px
-            let t_scale_level_width = (self.image.get_width() as f32 / scale as f32).floor() as i32;
-            let t_scale_level_height =
-                (self.image.get_height() as f32 / scale as f32).floor() as i32;
-            //calculate number of tiles along either axis
-            let mut t_tile_num_width =
-                (t_scale_level_width as f32 / self.image.get_tile_width() as f32).floor() as i32;
-            let mut t_tile_num_height =
-                (t_scale_level_height as f32 / self.image.get_tile_height() as f32).floor() as i32;
-            //add extra images on either axis as needed if the tile size doesn't evenly divide the axis length
-            if (t_scale_level_width % self.image.get_tile_width()) != 0 {
-                t_tile_num_width += 1;
+    // writes the image under the first size directory once for every rotation, quality and output
+    // format, then links each file into the other size directories. Rotations are only written when
+    // `rotate` is set.
+    fn save_variants(
+        &self,
+        image: &DynamicImage,
+        size_dirs: &[PathBuf],
+        rotate: bool,
+    ) -> Result<(), TilerError> {
+        let Some((size_dir, other_dirs)) = size_dirs.split_first() else {
+            return Ok(());
+        };
+        let mut rotations = vec![("0", Cow::Borrowed(image))];
+        if rotate {
+            for rotation in &self.rotations {
+                rotations.push((rotation.name(), Cow::Owned(rotation.render(image))));
             }
-            if (t_scale_level_height % self.image.get_tile_height()) != 0 {
-                t_tile_num_height += 1;
+        }
+
+        for (rotation, rotated) in &rotations {
+            let mut variants = vec![("default", Cow::Borrowed(&**rotated))];
+            for quality in &self.qualities {
+                variants.push((
+                    quality.name(),
+                    Cow::Owned(quality.render(rotated, self.bitonal)),
+                ));
             }
 
-            //make tiles
-            for x in 0..t_tile_num_width {
-                for y in 0..t_tile_num_height {
-                    let tile_x = x * self.image.get_tile_width() * scale;
-                    let tile_y = y * self.image.get_tile_height() * scale;
-                    let mut scaled_tile_width = self.image.get_tile_width() * scale;
-                    let mut tiled_width_calc = self.image.get_tile_width();
-                    if tile_x + scaled_tile_width > self.image.get_width() {
-                        let new_width = self.image.get_width() - tile_x;
-                        scaled_tile_width = new_width;
-                        tiled_width_calc = (new_width as f32 / scale as f32).ceil() as i32;
-                    }
-                    let mut scaled_tile_height = self.image.get_tile_height() * scale;
-                    let mut tiled_height_calc = self.image.get_tile_height();
-                    if tile_y + scaled_tile_height > self.image.get_height() {
-                        let new_height = self.image.get_height() - tile_y;
-                        scaled_tile_height = new_height;
-                        tiled_height_calc = (new_height as f32 / scale as f32).ceil() as i32;
+            for (quality, variant) in &variants {
+                for format in &self.formats {
+                    let file_name = format!("{}.{}", quality, format.extension());
+                    let output_path = size_dir.join(rotation).join(&file_name);
+                    save_image(
+                        variant,
+                        &output_path,
+                        *format,
+                        &self.encoder,
+                        &self.icc_profiles,
+                    )?;
+                    for other_dir in other_dirs {
+                        link_or_copy(&output_path, &other_dir.join(rotation).join(&file_name))?;
                     }
+                }
+            }
+        }
+        Ok(())
+    }
 
-                    let url = if *self.version == IIIFVersion::VERSION3 {
-                        // formatting path for v3
-                        format!(
-                            "/{},{},{},{}/{},{}/0/default.jpg",
-                            tile_x,
-                            tile_y,
-                            scaled_tile_width,
-                            scaled_tile_height,
-                            tiled_width_calc,
-                            tiled_height_calc
-                        )
-                    } else {
-                        // formatting path for v2.1
-                        format!(
-                            "/{},{},{},{}/{},/0/default.jpg",
-                            tile_x, tile_y, scaled_tile_width, scaled_tile_height, tiled_width_calc
-                        )
-                    };
-
-                    let t_output_file = PathBuf::from(format!("{}/{}", p_image_dir, url));
-                    if let Some(parent_dir) = t_output_file.parent() {
-                        if let Err(e) = create_dir_all(parent_dir) {
-                            eprintln!("Failed to create directory {}: {}", parent_dir.display(), e)
-                        }
-                    }
+    // downsamples each level from the one above, largest first. Each level is the size
+    // listed in the image info for its scale factor.
+    fn build_levels<'s>(&self, source: &'s DynamicImage) -> Vec<(i32, Cow<'s, DynamicImage>)> {
+        let mut scale_factors = self.image.get_scale_factors();
+        scale_factors.sort();
 
-                    let tile_image = self
-                        .image
-                        .get_image()
-                        .get_image()
-                        .crop_imm(
-                            tile_x as u32,
-                            tile_y as u32,
-                            scaled_tile_width as u32,
-                            scaled_tile_height as u32,
-                        )
-                        .into_rgb8();
-
-                    let scaled_image = if tile_image.width() == tiled_width_calc as u32
-                        && tile_image.height() == tiled_height_calc as u32
-                    {
-                        // No resize needed, use original image
-                        DynamicImage::ImageRgb8(tile_image)
-                    } else {
-                        // Choose filter type based on target dimensions
-                        let filter_type = if tiled_width_calc > 3 && tiled_height_calc > 3 {
-                            image::imageops::FilterType::CatmullRom
-                        } else {
-                            image::imageops::FilterType::Lanczos3
-                        };
-
-                        // Resize with selected filter type
-                        DynamicImage::ImageRgb8(tile_image).resize(
-                            tiled_width_calc as u32,
-                            tiled_height_calc as u32,
-                            filter_type,
-                        )
-                    };
-
-                    match scaled_image.save(&t_output_file) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            eprintln!(
-                                "Failed to write: '{:?}' ({},{}) - Error: {}",
-                                t_output_file.display(),
-                                scaled_image.width(),
-                                scaled_image.height(),
-                                e
-                            );
-                        }
-                    }
+        let mut levels: Vec<(i32, Cow<DynamicImage>)> = Vec::new();
+        for scale in scale_factors {
+            let level = match levels.last() {
+                Some((_, previous_level)) => {
+                    let width = (self.image.get_width() as f64 / scale as f64).ceil() as u32;
+                    let height = (self.image.get_height() as f64 / scale as f64).ceil() as u32;
+                    Cow::Owned(previous_level.resize_exact(width, height, self.filter))
                 }
+                // the largest scale factor is always 1, the full image, which we borrow
+                None => Cow::Borrowed(source),
+            };
+            levels.push((scale, level));
+        }
+        levels
+    }
+
+    fn _generate_level_tiles(
+        &self,
+        p_image_dirs: &[(IIIFVersion, PathBuf)],
+        levels: &[(i32, Cow<DynamicImage>)],
+    ) -> Vec<Result<(), TilerError>> {
+        let tiles: Vec<(&DynamicImage, TileRegion)> = levels
+            .iter()
+            .flat_map(|(scale, level)| {
+                self.tile_regions(*scale)
+                    .into_iter()
+                    .map(move |tile| (level.as_ref(), tile))
+            })
+            .collect();
+        self.write_all(&tiles, |(level, tile)| {
+            // tiles start on multiples of the scaled tile size so this divides exactly
+            let tile_image = level.crop_imm(
+                (tile.x / tile.scale) as u32,
+                (tile.y / tile.scale) as u32,
+                tile.scaled_width as u32,
+                tile.scaled_height as u32,
+            );
+            self.save_variants(
+                &tile_image,
+                &self.tile_dirs(p_image_dirs, tile),
+                self.rotate_tiles,
+            )
+        })
+    }
+
+    fn _generate_scale_tiles(
+        &self,
+        source: &DynamicImage,
+        p_image_dirs: &[(IIIFVersion, PathBuf)],
+    ) -> Vec<Result<(), TilerError>> {
+        let tiles: Vec<TileRegion> = self
+            .image
+            .get_scale_factors()
+            .into_iter()
+            .flat_map(|scale| self.tile_regions(scale))
+            .collect();
+        self.write_all(&tiles, |tile| self.write_tile(source, p_image_dirs, tile))
+    }
+
+    // works out every tile needed at the given scale factor
+    fn tile_regions(&self, scale: i32) -> Vec<TileRegion> {
+        //height in units of scale rather than px
+        let t_scale_level_width = (self.image.get_width() as f32 / scale as f32).floor() as i32;
+        let t_scale_level_height = (self.image.get_height() as f32 / scale as f32).floor() as i32;
+        //calculate number of tiles along either axis
+        let mut t_tile_num_width =
+            (t_scale_level_width as f32 / self.image.get_tile_width() as f32).floor() as i32;
+        let mut t_tile_num_height =
+            (t_scale_level_height as f32 / self.image.get_tile_height() as f32).floor() as i32;
+        //add extra images on either axis as needed if the tile size doesn't evenly divide the axis length
+        if (t_scale_level_width % self.image.get_tile_width()) != 0 {
+            t_tile_num_width += 1;
+        }
+        if (t_scale_level_height % self.image.get_tile_height()) != 0 {
+            t_tile_num_height += 1;
+        }
+
+        let mut tiles = Vec::new();
+        for x in 0..t_tile_num_width {
+            for y in 0..t_tile_num_height {
+                let tile_x = x * self.image.get_tile_width() * scale;
+                let tile_y = y * self.image.get_tile_height() * scale;
+                let mut scaled_tile_width = self.image.get_tile_width() * scale;
+                let mut tiled_width_calc = self.image.get_tile_width();
+                if tile_x + scaled_tile_width > self.image.get_width() {
+                    let new_width = self.image.get_width() - tile_x;
+                    scaled_tile_width = new_width;
+                    tiled_width_calc = (new_width as f32 / scale as f32).ceil() as i32;
+                }
+                let mut scaled_tile_height = self.image.get_tile_height() * scale;
+                let mut tiled_height_calc = self.image.get_tile_height();
+                if tile_y + scaled_tile_height > self.image.get_height() {
+                    let new_height = self.image.get_height() - tile_y;
+                    scaled_tile_height = new_height;
+                    tiled_height_calc = (new_height as f32 / scale as f32).ceil() as i32;
+                }
+                tiles.push(TileRegion {
+                    scale,
+                    x: tile_x,
+                    y: tile_y,
+                    width: scaled_tile_width,
+                    height: scaled_tile_height,
+                    scaled_width: tiled_width_calc,
+                    scaled_height: tiled_height_calc,
+                });
             }
         }
-        Ok(())
+        tiles
+    }
+
+    // the directories for a tile's region and size in each version's layout, which hold its rotations
+    fn tile_dirs(
+        &self,
+        p_image_dirs: &[(IIIFVersion, PathBuf)],
+        tile: &TileRegion,
+    ) -> Vec<PathBuf> {
+        let region = format!("{},{},{},{}", tile.x, tile.y, tile.width, tile.height);
+        p_image_dirs
+            .iter()
+            .map(|(version, p_image_dir)| {
+                let size = if *version == IIIFVersion::VERSION3 {
+                    // formatting path for v3
+                    format!("{},{}", tile.scaled_width, tile.scaled_height)
+                } else {
+                    // formatting path for v2.1
+                    format!("{},", tile.scaled_width)
+                };
+                p_image_dir.join(&region).join(size)
+            })
+            .collect()
+    }
+
+    fn write_tile(
+        &self,
+        source: &DynamicImage,
+        p_image_dirs: &[(IIIFVersion, PathBuf)],
+        tile: &TileRegion,
+    ) -> Result<(), TilerError> {
+        let t_output_dirs = self.tile_dirs(p_image_dirs, tile);
+
+        // crop straight out of the borrowed source so only the tile's region is copied
+        let tile_image = source.crop_imm(
+            tile.x as u32,
+            tile.y as u32,
+            tile.width as u32,
+            tile.height as u32,
+        );
+
+        let scaled_image = if tile_image.width() == tile.scaled_width as u32
+            && tile_image.height() == tile.scaled_height as u32
+        {
+            // No resize needed, use original image
+            tile_image
+        } else {
+            // Choose filter type based on target dimensions
+            let filter_type = if tile.scaled_width > 3 && tile.scaled_height > 3 {
+                FilterType::CatmullRom
+            } else {
+                FilterType::Lanczos3
+            };
+
+            // Resize with selected filter type
+            tile_image.resize(
+                tile.scaled_width as u32,
+                tile.scaled_height as u32,
+                filter_type,
+            )
+        };
+
+        self.save_variants(&scaled_image, &t_output_dirs, self.rotate_tiles)
     }
 
     // Tiles a single image, returning the manifest in json form
@@ -190,26 +761,126 @@ impl<'a> Tiler<'a> {
         output_dir: &str,
         uri: &str,
         version: &IIIFVersion,
-    ) -> Result<String, Error> {
-        let tiler = Tiler::new(image, version);
-        match tiler.generate_tiles(output_dir) {
-            Ok(_) => {}
-            Err(e) => return Err(Error::msg(format!("Failed to generate tiles: {}", e))),
+    ) -> Result<String, TilerError> {
+        Tiler::new(image, version).create(output_dir, uri)
+    }
+
+    // Tiles the image with this tiler's settings, returning the manifest in json form.
+    // With several versions this is the first version's manifest.
+    // No manifest is returned if any tile failed to write.
+    pub fn create(&self, output_dir: &str, uri: &str) -> Result<String, TilerError> {
+        Ok(self.create_all(output_dir, uri)?.remove(0).1)
+    }
+
+    /// Tiles the image with this tiler's settings, returning every version's manifest in json form.
+    pub fn create_all(
+        &self,
+        output_dir: &str,
+        uri: &str,
+    ) -> Result<Vec<(IIIFVersion, String)>, TilerError> {
+        self.generate_tiles(output_dir)?;
+        self.manifests(uri, None)
+    }
+
+    // builds each version's manifest. With several versions each one's id is under its directory.
+    fn manifests(
+        &self,
+        uri: &str,
+        sizes: Option<Vec<(i32, i32)>>,
+    ) -> Result<Vec<(IIIFVersion, String)>, TilerError> {
+        let mut manifests = Vec::new();
+        for version in &self.versions {
+            let version_uri = if self.versions.len() > 1 {
+                format!("{}{}/", uri, version.dir_name())
+            } else {
+                uri.to_owned()
+            };
+            let mut info = InfoJSON::new(self.image, &version_uri, version);
+            info.set_formats(self.formats.clone());
+            info.set_qualities(self.qualities.clone());
+            // a level 0 client asks for rotated tiles too, so only advertise rotations when they were written
+            if self.rotate_tiles {
+                info.set_rotations(self.rotations.clone());
+            }
+            info.set_properties(self.properties.clone());
+            if let Some(sizes) = &sizes {
+                info.set_sizes(sizes.clone());
+            }
+            let manifest = if self.pretty {
+                info.to_json_pretty()
+            } else {
+                info.to_json()
+            };
+            manifests.push((*version, manifest?));
         }
-        let info = InfoJSON::new(image, uri, version);
+        Ok(manifests)
+    }
+
+    // Streams the image from disk with generate_tiles_streaming, returning the manifest in json form.
+    // With several versions this is the first version's manifest.
+    pub fn create_streaming(
+        &self,
+        source: &Path,
+        output_dir: &str,
+        uri: &str,
+    ) -> Result<String, TilerError> {
+        Ok(self
+            .create_streaming_all(source, output_dir, uri)?
+            .remove(0)
+            .1)
+    }
 
-        Ok(info.to_json()?)
+    /// Streams the image from disk with [`Tiler::generate_tiles_streaming`], returning every version's
+    /// manifest in json form.
+    pub fn create_streaming_all(
+        &self,
+        source: &Path,
+        output_dir: &str,
+        uri: &str,
+    ) -> Result<Vec<(IIIFVersion, String)>, TilerError> {
+        let sizes = self.generate_tiles_streaming(source, output_dir)?;
+        self.manifests(uri, Some(sizes))
     }
 }
 
 // helper function for image saving
-fn save_image(image: &DynamicImage, path: &Path) -> Result<(), Error> {
+fn save_image(
+    image: &DynamicImage,
+    path: &Path,
+    format: OutputFormat,
+    encoder: &EncoderSettings,
+    icc_profiles: &IccProfiles,
+) -> Result<(), TilerError> {
     if let Some(parent_dir) = path.parent() {
-        create_dir_all(parent_dir)
-            .map_err(|_e| Error::msg(format!("Failed to create directory: {:?}", parent_dir)))?;
+        create_dir_all(parent_dir).map_err(|e| TilerError::Io {
+            path: parent_dir.to_path_buf(),
+            source: e,
+        })?;
     }
 
-    image
-        .save(path)
-        .map_err(|_e| Error::msg(format!("Failed to save image: {:?}", path)))
+    encoder.save(image, path, format, icc_profiles)
+}
+
+// hard links an already written file to another path, copying it if the link can't be made,
+// e.g. across file systems
+fn link_or_copy(from: &Path, to: &Path) -> Result<(), TilerError> {
+    let io_error = |source| TilerError::Io {
+        path: to.to_path_buf(),
+        source,
+    };
+    if let Some(parent_dir) = to.parent() {
+        create_dir_all(parent_dir).map_err(|e| TilerError::Io {
+            path: parent_dir.to_path_buf(),
+            source: e,
+        })?;
+    }
+    // replace anything left from an earlier run
+    match fs::remove_file(to) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(io_error(e)),
+        _ => {}
+    }
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to).map_err(io_error)?;
+    }
+    Ok(())
 }
@@ -2,11 +2,21 @@
 use std::fs;
 use std::path::Path;
 
-use iiif_tiler_rust::iiif_image::IIIFImage;
+use iiif_tiler_rust::error::TilerError;
+use iiif_tiler_rust::iiif_image::{
+    read_icc_profile, read_orientation, IIIFImage, IccMode, IdentifierStrategy, LoadOptions,
+    OrientationMode, PixelMode,
+};
 use iiif_tiler_rust::image_info::ImageInfo;
-use iiif_tiler_rust::info_json::{IIIFVersion, InfoJSON};
-use iiif_tiler_rust::tiler::Tiler;
-
+use iiif_tiler_rust::image_service::{ImageService, ProfileEntry};
+use iiif_tiler_rust::info_json::{encode_id, IIIFVersion, InfoJSON, Properties};
+use iiif_tiler_rust::output::{
+    Bitonal, ChromaSubsampling, EncoderSettings, OutputFormat, PngCompression, Quality, Rotation,
+};
+use iiif_tiler_rust::presentation::{CollectionBuilder, Manifest2, Manifest3, ManifestBuilder};
+use iiif_tiler_rust::tiler::{Tiler, TilingMode};
+
+use image::metadata::Orientation;
 use serde_json::Value;
 use tempfile::TempDir;
 
@@ -34,8 +44,15 @@ fn test_version_2_json() {
         parsed["@context"],
         "http://iiif.io/api/image/2/context.json"
     );
-    assert_eq!(parsed["id"], "http://localhost:8887/iiif/test");
-    assert_eq!(parsed["profile"], "http://iiif.io/api/image/2/level0.json");
+    assert_eq!(parsed["@id"], "http://localhost:8887/iiif/test");
+    assert!(parsed.get("id").is_none());
+    assert_eq!(
+        parsed["profile"],
+        serde_json::json!([
+            "http://iiif.io/api/image/2/level0.json",
+            { "formats": ["jpg"], "qualities": ["default"], "supports": [] }
+        ])
+    );
     assert_eq!(parsed["protocol"], "http://iiif.io/api/image");
 
     // check image dimensions
@@ -108,6 +125,189 @@ fn test_version_3_json() {
     assert_eq!(scale_factors, vec![32, 16, 8, 4, 2, 1]);
 }
 
+#[test]
+fn test_spec_examples() -> Result<(), Box<dyn std::error::Error>> {
+    let examples = [
+        (
+            IIIFVersion::VERSION211,
+            "tests/fixtures/spec/image-2.1-info.json",
+            "http://www.example.org/image-service/abcd1234/",
+        ),
+        (
+            IIIFVersion::VERSION3,
+            "tests/fixtures/spec/image-3.0-info.json",
+            "https://example.org/image-service/abcd1234/",
+        ),
+    ];
+    for (version, path, uri) in &examples {
+        let spec: Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+        let info = ImageInfo::from_dimensions(
+            "1E34750D-38DB-4825-A38A-B60A345E591C",
+            6000,
+            4000,
+            512,
+            512,
+            4,
+        );
+        let mut info_json = InfoJSON::new(&info, uri, version);
+        info_json.set_sizes(vec![(150, 100), (600, 400), (3000, 2000)]);
+        info_json.set_formats(vec![OutputFormat::Png, OutputFormat::Gif]);
+        info_json.set_qualities(vec![Quality::Gray]);
+        info_json.set_rotations(vec![Rotation::Mirror]);
+        let json = info_json.to_json()?;
+        let parsed: Value = serde_json::from_str(&json)?;
+
+        // what's written reads back into the typed model unchanged
+        let written: Value = serde_json::from_str(&ImageService::from_json(&json)?.to_json()?)?;
+        assert_eq!(written, parsed, "{:?} changed in a round trip", version);
+
+        // only keys the spec's example uses, with the same values where they describe the same image
+        for key in parsed.as_object().unwrap().keys() {
+            assert!(
+                spec.get(key).is_some(),
+                "{} isn't in the {:?} spec",
+                key,
+                version
+            );
+        }
+        for key in [
+            "@context", "@id", "id", "type", "protocol", "width", "height", "sizes",
+        ] {
+            assert_eq!(parsed.get(key), spec.get(key), "{} differs", key);
+        }
+        assert_eq!(parsed["tiles"][0]["width"], spec["tiles"][0]["width"]);
+
+        if *version == IIIFVersion::VERSION211 {
+            let keys = |profile: &Value| -> Vec<String> {
+                profile[1].as_object().unwrap().keys().cloned().collect()
+            };
+            assert_eq!(keys(&parsed["profile"]), keys(&spec["profile"]));
+            assert_eq!(
+                parsed["profile"],
+                serde_json::json!([
+                    "http://iiif.io/api/image/2/level0.json",
+                    { "formats": ["png", "gif", "jpg"], "qualities": ["default", "gray"], "supports": ["mirroring"] }
+                ])
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_info_json() -> Result<(), Box<dyn std::error::Error>> {
+    // the spec's examples survive a round trip through the typed model
+    for path in [
+        "tests/fixtures/spec/image-2.1-info.json",
+        "tests/fixtures/spec/image-3.0-info.json",
+    ] {
+        let json = fs::read_to_string(path)?;
+        let service = ImageService::from_json(&json)?;
+        assert_eq!(service.width(), 6000);
+        assert_eq!(service.sizes().len(), 3);
+        let original: Value = serde_json::from_str(&json)?;
+        let written: Value = serde_json::from_str(&service.to_json()?)?;
+        assert_eq!(original, written, "{} changed in a round trip", path);
+    }
+
+    let json = fs::read_to_string("tests/fixtures/spec/image-2.1-info.json")?;
+    let ImageService::V2(service) = ImageService::from_json(&json)? else {
+        panic!("Expected a v2 image service");
+    };
+    let description = service.profile.iter().find_map(|entry| match entry {
+        ProfileEntry::Description(description) => Some(description),
+        ProfileEntry::Uri(_) => None,
+    });
+    assert_eq!(
+        description.unwrap().qualities,
+        Some(vec!["color".to_string(), "gray".to_string()])
+    );
+
+    // and so do the ones the tiler writes, compact or pretty
+    let image = IIIFImage::new("tests/fixtures/test.jpg");
+    let info = ImageInfo::new(&image, 1024, 1024, 5);
+    for version in [IIIFVersion::VERSION211, IIIFVersion::VERSION3] {
+        let mut info_json = InfoJSON::new(&info, "http://localhost:8887/iiif/", &version);
+        info_json.set_formats(vec![OutputFormat::Jpg, OutputFormat::Webp]);
+        info_json.set_qualities(vec![Quality::Gray]);
+        let compact = info_json.to_json()?;
+        let pretty = info_json.to_json_pretty()?;
+        assert!(!compact.contains('\n'));
+        assert!(pretty.contains('\n'));
+        for json in [compact, pretty] {
+            let service = ImageService::from_json(&json)?;
+            assert_eq!(service, info_json.to_service());
+            assert_eq!(service.id(), "http://localhost:8887/iiif/test");
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_properties() -> Result<(), Box<dyn std::error::Error>> {
+    let properties = Properties::from_toml(
+        r#"
+        rights = "http://rightsstatements.org/vocab/InC-EDU/1.0/"
+        attribution = "Provided by Example Organization"
+        partOf = [{ id = "https://example.org/manifest/1", type = "Manifest" }]
+        logo = { "@id" = "https://example.org/logo.png" }
+
+        [[service]]
+        "@context" = "http://iiif.io/api/annex/services/physdim/1/context.json"
+        profile = "http://iiif.io/api/annex/services/physdim"
+        physicalScale = 0.0025
+        physicalUnits = "in"
+        "#,
+    )?;
+    assert_eq!(
+        properties,
+        Properties::from_json(&serde_json::to_string(&properties)?)?
+    );
+
+    let image = IIIFImage::new("tests/fixtures/test.jpg");
+    let info = ImageInfo::new(&image, 1024, 1024, 5);
+
+    let version = IIIFVersion::VERSION3;
+    let mut info_json = InfoJSON::new(&info, "http://localhost:8887/iiif/", &version);
+    info_json.set_properties(properties.clone());
+    info_json.set_property("x-local", serde_json::json!(true));
+    let parsed: Value = serde_json::from_str(&info_json.to_json()?)?;
+    assert_eq!(
+        parsed["rights"],
+        "http://rightsstatements.org/vocab/InC-EDU/1.0/"
+    );
+    assert_eq!(parsed["partOf"][0]["type"], "Manifest");
+    assert_eq!(parsed["service"][0]["physicalUnits"], "in");
+    assert_eq!(parsed["x-local"], true);
+    for v2_only in ["attribution", "logo", "license"] {
+        assert!(parsed.get(v2_only).is_none(), "{} is v2 only", v2_only);
+    }
+
+    let version = IIIFVersion::VERSION211;
+    let mut info_json = InfoJSON::new(&info, "http://localhost:8887/iiif/", &version);
+    info_json.set_properties(properties);
+    let parsed: Value = serde_json::from_str(&info_json.to_json()?)?;
+    assert_eq!(
+        parsed["license"],
+        "http://rightsstatements.org/vocab/InC-EDU/1.0/"
+    );
+    assert_eq!(parsed["attribution"], "Provided by Example Organization");
+    assert_eq!(parsed["logo"]["@id"], "https://example.org/logo.png");
+    assert_eq!(parsed["service"][0]["physicalScale"], 0.0025);
+    for v3_only in ["rights", "partOf"] {
+        assert!(parsed.get(v3_only).is_none(), "{} is v3 only", v3_only);
+    }
+
+    assert!(matches!(
+        Properties::from_json("{\"rights\": 3}"),
+        Err(TilerError::InvalidParameter(_))
+    ));
+
+    Ok(())
+}
+
 #[test]
 fn test_sizes() -> Result<(), Box<dyn std::error::Error>> {
     let tmp_dir = TempDir::new()?;
@@ -124,10 +324,9 @@ fn test_sizes() -> Result<(), Box<dyn std::error::Error>> {
 
     // Test that canonical sizes exist
     let sizes = image_info.get_sizes();
-    for size in sizes {
-        let width = size.0;
-
-        let size_image_path = output_dir.join(format!("test/full/{},/0/default.jpg", width));
+    for (width, height) in sizes {
+        let size_image_path =
+            output_dir.join(format!("test/full/{},{}/0/default.jpg", width, height));
 
         assert!(
             size_image_path.exists(),
@@ -196,7 +395,8 @@ fn test_check_count() -> Result<(), Box<dyn std::error::Error>> {
 
     let image_info = ImageInfo::new(&image, 256, 256, 4);
 
-    let predicted_count = image_info.calculate_file_count();
+    // generate_tiles doesn't write the info.json the count includes
+    let predicted_count = image_info.calculate_file_count() - 1;
     let version = IIIFVersion::VERSION211;
 
     let tiler = Tiler::new(&image_info, &version);
@@ -224,7 +424,8 @@ fn test_check_count_large() -> Result<(), Box<dyn std::error::Error>> {
 
     let image_info = ImageInfo::new(&image, 512, 512, 4);
 
-    let predicted_count = image_info.calculate_file_count();
+    // generate_tiles doesn't write the info.json the count includes
+    let predicted_count = image_info.calculate_file_count() - 1;
     let version = IIIFVersion::VERSION211;
 
     let tiler = Tiler::new(&image_info, &version);
@@ -253,7 +454,8 @@ fn test_smaller_zoom_level() -> Result<(), Box<dyn std::error::Error>> {
 
     let image_info = ImageInfo::new(&image, 256, 256, 3);
 
-    let predicted_count = image_info.calculate_file_count() + 1;
+    // generate_tiles doesn't write the info.json the count includes
+    let predicted_count = image_info.calculate_file_count() - 1;
     let version = IIIFVersion::VERSION211;
 
     let tiler = Tiler::new(&image_info, &version);
@@ -284,7 +486,8 @@ fn test_limit_to_100() -> Result<(), Box<dyn std::error::Error>> {
 
     image_info.fit_to_max_file_no(100);
 
-    let predicted_count = image_info.calculate_file_count() + 1;
+    // generate_tiles doesn't write the info.json the count includes
+    let predicted_count = image_info.calculate_file_count() - 1;
     let version = IIIFVersion::VERSION211;
 
     let tiler = Tiler::new(&image_info, &version);
@@ -320,7 +523,8 @@ fn test_limit_to_100_large() -> Result<(), Box<dyn std::error::Error>> {
 
     image_info.fit_to_max_file_no(100);
 
-    let predicted_count = image_info.calculate_file_count();
+    // generate_tiles doesn't write the info.json the count includes
+    let predicted_count = image_info.calculate_file_count() - 1;
     let version = IIIFVersion::VERSION211;
 
     let tiler = Tiler::new(&image_info, &version);
@@ -356,14 +560,14 @@ fn test_rounding() -> Result<(), Box<dyn std::error::Error>> {
     let tiler = Tiler::new(&image_info, &IIIFVersion::VERSION3);
     tiler.generate_tiles(&output_dir.to_string_lossy())?;
 
-    let incorrect_path = output_dir.join("tractor/full/503,/0/default.jpg");
+    let incorrect_path = output_dir.join("tractor/full/503,377/0/default.jpg");
     assert!(
         !incorrect_path.exists(),
-        "Rounded down instead of UP. Found {} expected tractor/full/504,/0/default.jpg",
+        "Rounded down instead of UP. Found {} expected tractor/full/504,378/0/default.jpg",
         incorrect_path.display()
     );
 
-    let correct_path = output_dir.join("tractor/full/504,/0/default.jpg");
+    let correct_path = output_dir.join("tractor/full/504,378/0/default.jpg");
     assert!(
         correct_path.exists(),
         "Correct rounding should exist. Didn't find {}",
@@ -524,30 +728,1889 @@ fn test_alpha_channel() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-//Helper functions for tests
+#[test]
+fn test_open_missing_file() {
+    let result = IIIFImage::open("tests/fixtures/does-not-exist.jpg");
+    assert!(
+        matches!(result, Err(TilerError::Io { .. })),
+        "Expected an I/O error for a missing file"
+    );
+}
 
-/// Recursively count files in a directory
-fn count_files(directory: &Path) -> Result<Vec<String>, std::io::Error> {
-    let mut files = Vec::new();
+#[test]
+fn test_open_corrupt_image() {
+    let result = IIIFImage::from_bytes(b"definitely not an image", "corrupt");
+    assert!(
+        matches!(result, Err(TilerError::Decode { path: None, .. })),
+        "Expected a decode error for a corrupt buffer"
+    );
+}
 
-    // Optional debug output
-    // if fs::read_dir(directory)?.count() > 1 {
-    //     println!("Multiple {}", directory.display());
-    // }
+#[test]
+fn test_from_bytes() -> Result<(), Box<dyn std::error::Error>> {
+    let bytes = fs::read("tests/fixtures/test.jpg")?;
+    let from_memory = IIIFImage::from_bytes(&bytes, "in-memory")?;
+    let from_file = IIIFImage::open("tests/fixtures/test.jpg")?;
 
-    for entry in fs::read_dir(directory)? {
-        let entry = entry?;
-        let path = entry.path();
+    assert_eq!(from_memory.id(), "in-memory");
+    assert_eq!(from_file.id(), "test");
+    assert_eq!(from_memory.get_width(), from_file.get_width());
+    assert_eq!(from_memory.get_height(), from_file.get_height());
 
-        if let Some(path_str) = path.to_str() {
-            files.push(path_str.to_string());
+    Ok(())
+}
+
+#[test]
+fn test_unwritable_output_is_io_error() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    // a plain file where the output directory should be, so no directory can be created under it
+    let output_dir = tmp_dir.path().join("iiif");
+    fs::write(&output_dir, b"")?;
+
+    let image = IIIFImage::open("tests/fixtures/test.jpg")?;
+    let image_info = ImageInfo::new(&image, 1024, 1024, 1);
+    let result = Tiler::create_image(
+        &image_info,
+        &output_dir.to_string_lossy(),
+        "http://localhost:8887/iiif/",
+        &IIIFVersion::VERSION3,
+    );
+
+    match result {
+        Err(TilerError::Tiles(failures)) => assert!(
+            failures.iter().all(|e| matches!(e, TilerError::Io { .. })),
+            "Expected I/O errors when the output directory can't be created"
+        ),
+        other => panic!("Expected tile failures, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_invalid_tiling_is_error() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let output_dir = tmp_dir.path().to_string_lossy().into_owned();
+    let source = tmp_dir.path().join("source.png");
+    image::RgbImage::from_pixel(64, 48, image::Rgb([10, 20, 30])).save(&source)?;
+    let image = IIIFImage::open(&source)?;
+    let version = IIIFVersion::VERSION3;
+
+    for (tile_size, zoom_levels) in [(0, 2), (-256, 2), (256, -1)] {
+        let image_info = ImageInfo::new(&image, tile_size, tile_size, zoom_levels);
+        let tiler = Tiler::new(&image_info, &version);
+        assert!(
+            matches!(
+                tiler.generate_tiles(&output_dir),
+                Err(TilerError::InvalidParameter(_))
+            ),
+            "tile size {} and zoom levels {}",
+            tile_size,
+            zoom_levels
+        );
+
+        let image_info =
+            ImageInfo::from_dimensions("source", 64, 48, tile_size, tile_size, zoom_levels);
+        let tiler = Tiler::new(&image_info, &version);
+        assert!(matches!(
+            tiler.generate_tiles_streaming(&source, &output_dir),
+            Err(TilerError::InvalidParameter(_))
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_tile_failures_are_reported() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let output_dir = tmp_dir.path().join("iiif");
+    // block one tile by putting a file where its region directory should be
+    fs::create_dir_all(output_dir.join("exact_tiles"))?;
+    fs::write(output_dir.join("exact_tiles/0,0,1024,1024"), b"")?;
+
+    let image = IIIFImage::open("tests/fixtures/exact_tiles.jpg")?;
+    let image_info = ImageInfo::new(&image, 1024, 1024, 1);
+    let version = IIIFVersion::VERSION211;
+
+    let tiler = Tiler::new(&image_info, &version);
+    let result = tiler.create(&output_dir.to_string_lossy(), "http://localhost:8887/iiif/");
+    match result {
+        Err(TilerError::Tiles(failures)) => assert_eq!(failures.len(), 1),
+        other => panic!("Expected a single tile failure, got {:?}", other),
+    }
+    // the remaining tiles are still written
+    assert!(output_dir
+        .join("exact_tiles/1024,1024,1024,1024/1024,/0/default.jpg")
+        .exists());
+
+    let mut tiler = Tiler::new(&image_info, &version);
+    tiler.set_fail_fast(true);
+    let result = tiler.create(&output_dir.to_string_lossy(), "http://localhost:8887/iiif/");
+    assert!(
+        matches!(result, Err(TilerError::Io { .. })),
+        "Expected the first failure to be returned when failing fast"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_parallel_matches_serial() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let serial_dir = tmp_dir.path().join("serial");
+    let parallel_dir = tmp_dir.path().join("parallel");
+
+    let image = IIIFImage::open("tests/fixtures/test.jpg")?;
+    let image_info = ImageInfo::new(&image, 256, 256, 3);
+    let version = IIIFVersion::VERSION3;
+
+    let mut tiler = Tiler::new(&image_info, &version);
+    tiler.set_threads(1);
+    tiler.generate_tiles(&serial_dir.to_string_lossy())?;
+    tiler.set_threads(4);
+    tiler.generate_tiles(&parallel_dir.to_string_lossy())?;
+
+    let mut serial_files = count_files(&serial_dir)?;
+    serial_files.sort();
+    assert_eq!(serial_files.len(), count_files(&parallel_dir)?.len());
+    for serial_file in serial_files {
+        let parallel_file = Path::new(&serial_file)
+            .strip_prefix(&serial_dir)
+            .map(|relative| parallel_dir.join(relative))?;
+        if Path::new(&serial_file).is_file() {
+            assert_eq!(
+                fs::read(&serial_file)?,
+                fs::read(&parallel_file)?,
+                "{} differs between serial and parallel runs",
+                parallel_file.display()
+            );
         }
+    }
 
-        if path.is_dir() {
-            let mut sub_files = count_files(&path)?;
-            files.append(&mut sub_files);
+    Ok(())
+}
+
+#[test]
+fn test_pyramid_mode() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let full_dir = tmp_dir.path().join("full");
+    let pyramid_dir = tmp_dir.path().join("pyramid");
+
+    let image = IIIFImage::open("tests/fixtures/odd-sized.jpg")?;
+    let image_info = ImageInfo::new(&image, 512, 512, 3);
+    let version = IIIFVersion::VERSION3;
+
+    let tiler = Tiler::new(&image_info, &version);
+    tiler.generate_tiles(&full_dir.to_string_lossy())?;
+    let mut tiler = Tiler::new(&image_info, &version);
+    tiler.set_mode(TilingMode::Pyramid);
+    tiler.generate_tiles(&pyramid_dir.to_string_lossy())?;
+
+    // the pyramid writes the same tiles and sizes
+    let mut full_files = count_files(&full_dir)?;
+    full_files.sort();
+    let mut pyramid_files = count_files(&pyramid_dir)?;
+    pyramid_files.sort();
+    assert_eq!(full_files.len(), pyramid_files.len());
+    for (full_file, pyramid_file) in full_files.iter().zip(pyramid_files.iter()) {
+        assert_eq!(
+            Path::new(full_file).strip_prefix(&full_dir)?,
+            Path::new(pyramid_file).strip_prefix(&pyramid_dir)?
+        );
+    }
+
+    // and every tile is exactly the size its path asks for
+    assert_sizes_match_paths(&pyramid_files)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_borrowed_views() -> Result<(), Box<dyn std::error::Error>> {
+    use image::GenericImageView;
+
+    let image = IIIFImage::open("tests/fixtures/test.jpg")?;
+    let image_info = ImageInfo::new(&image, 1024, 1024, 1);
+
+    // the image info hands out the same decoded image rather than a copy
+    assert!(std::ptr::eq(image.image(), image_info.image()));
+    assert!(std::ptr::eq(&image, image_info.get_image()));
+    assert!(image_info.has_image());
+
+    let view = image.view(100, 200, 64, 32);
+    assert_eq!(view.dimensions(), (64, 32));
+    assert_eq!(view.get_pixel(5, 7), image.image().get_pixel(105, 207));
+
+    Ok(())
+}
+
+#[test]
+fn test_streaming() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let source = image::open("tests/fixtures/odd-sized.jpg")?;
+    let pyramid_dir = tmp_dir.path().join("pyramid");
+
+    let image = IIIFImage::open("tests/fixtures/odd-sized.jpg")?;
+    let image_info = ImageInfo::new(&image, 512, 512, 3);
+    let version = IIIFVersion::VERSION3;
+    let mut tiler = Tiler::new(&image_info, &version);
+    tiler.set_mode(TilingMode::Pyramid);
+    tiler.generate_tiles(&pyramid_dir.to_string_lossy())?;
+    let mut pyramid_files: Vec<String> = count_files(&pyramid_dir.join("odd-sized"))?
+        .iter()
+        .map(|f| f.replacen(&*pyramid_dir.to_string_lossy(), "", 1))
+        .collect();
+    pyramid_files.sort();
+
+    for extension in ["png", "tif"] {
+        let source_path = tmp_dir.path().join(format!("odd-sized.{}", extension));
+        source.save(&source_path)?;
+        let output_dir = tmp_dir.path().join(extension);
+
+        let image_info = ImageInfo::from_dimensions(
+            "odd-sized",
+            source.width() as i32,
+            source.height() as i32,
+            512,
+            512,
+            3,
+        );
+        assert!(!image_info.has_image());
+        let tiler = Tiler::new(&image_info, &version);
+        let json = tiler.create_streaming(
+            &source_path,
+            &output_dir.to_string_lossy(),
+            "http://localhost:8887/iiif/",
+        )?;
+
+        // streaming writes the same tiles and sizes as the in memory pyramid
+        let mut streamed_files: Vec<String> = count_files(&output_dir.join("odd-sized"))?
+            .iter()
+            .map(|f| f.replacen(&*output_dir.to_string_lossy(), "", 1))
+            .collect();
+        streamed_files.sort();
+        assert_eq!(pyramid_files, streamed_files);
+        assert_sizes_match_paths(&count_files(&output_dir)?)?;
+
+        let parsed: Value = serde_json::from_str(&json)?;
+        assert_eq!(parsed["sizes"].as_array().unwrap().len(), 4);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_streaming_memory_budget() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let source_path = tmp_dir.path().join("odd-sized.png");
+    let source = image::open("tests/fixtures/odd-sized.jpg")?;
+    source.save(&source_path)?;
+    let output_dir = tmp_dir.path().join("iiif");
+
+    let image_info = ImageInfo::from_dimensions(
+        "odd-sized",
+        source.width() as i32,
+        source.height() as i32,
+        512,
+        512,
+        3,
+    );
+    let version = IIIFVersion::VERSION3;
+    let mut tiler = Tiler::new(&image_info, &version);
+
+    // not even enough for a band of rows
+    tiler.set_memory_budget(1024);
+    let result = tiler.generate_tiles_streaming(&source_path, &output_dir.to_string_lossy());
+    assert!(matches!(result, Err(TilerError::InvalidParameter(_))));
+
+    let band_bytes: usize = [1, 2, 4, 8]
+        .iter()
+        .map(|scale| (2 * 512 + 1) * source.width().div_ceil(*scale) as usize * 3)
+        .sum();
+    let size_bytes = |scale: u32| {
+        (source.width().div_ceil(scale) * source.height().div_ceil(scale)) as usize * 3
+    };
+
+    let all_sizes = image_info.get_sizes();
+
+    // the bands and the two smallest sizes, but not the full sized image, which is left out
+    tiler.set_memory_budget(band_bytes + size_bytes(4) + size_bytes(8));
+    let json = tiler.create_streaming(&source_path, &output_dir.to_string_lossy(), "")?;
+    assert_eq!(
+        listed_sizes(&json)?,
+        all_sizes[..2]
+            .iter()
+            .map(|&(width, height)| (width as i64, height as i64))
+            .collect::<Vec<_>>()
+    );
+    assert!(!output_dir.join("odd-sized/full/max").exists());
+    assert!(output_dir
+        .join("odd-sized/0,0,512,512/512,512/0/default.jpg")
+        .exists());
+    fs::remove_dir_all(&output_dir)?;
+
+    // enough for the bands, the full sized image and the smallest size only
+    tiler.set_memory_budget(band_bytes + size_bytes(1) + size_bytes(8));
+    let sizes = tiler.generate_tiles_streaming(&source_path, &output_dir.to_string_lossy())?;
+
+    assert_eq!(sizes, vec![all_sizes[0], *all_sizes.last().unwrap()]);
+    assert!(output_dir.join("odd-sized/full/max/0/default.jpg").exists());
+    assert!(!output_dir
+        .join(format!(
+            "odd-sized/full/{},{}/0/default.jpg",
+            all_sizes[1].0, all_sizes[1].1
+        ))
+        .exists());
+    assert!(output_dir
+        .join("odd-sized/0,0,512,512/512,512/0/default.jpg")
+        .exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_sizes_too_large_for_formats() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let source_path = tmp_dir.path().join("wide.png");
+    let source = image::RgbImage::from_pixel(70000, 8, image::Rgb([90, 120, 150]));
+    source.save(&source_path)?;
+    let version = IIIFVersion::VERSION3;
+
+    // a JPEG can't be 70000 pixels wide, so the full sized image is left out rather than failing
+    let image_info = ImageInfo::from_dimensions("wide", 70000, 8, 1024, 1024, 2);
+    let tiler = Tiler::new(&image_info, &version);
+    let output_dir = tmp_dir.path().join("streamed");
+    let json = tiler.create_streaming(&source_path, &output_dir.to_string_lossy(), "")?;
+    assert_eq!(listed_sizes(&json)?, vec![(17500, 2), (35000, 4)]);
+    assert!(!output_dir.join("wide/full/max").exists());
+    assert!(output_dir.join("wide/full/35000,4/0/default.jpg").exists());
+    assert!(output_dir
+        .join("wide/0,0,1024,8/1024,8/0/default.jpg")
+        .exists());
+
+    // decoded, it's left out the same way and the estimate counts what's written
+    let image = IIIFImage::open(&source_path)?;
+    let image_info = ImageInfo::new(&image, 1024, 1024, 2);
+    let mut tiler = Tiler::new(&image_info, &version);
+    let output_dir = tmp_dir.path().join("in_memory");
+    let json = tiler.create(&output_dir.to_string_lossy(), "")?;
+    assert_eq!(listed_sizes(&json)?, vec![(17500, 2), (35000, 4)]);
+    assert_eq!(
+        count_files(&output_dir)?.len() as i32,
+        image_info.calculate_file_count() - 1
+    );
+
+    // and WebP can't hold any of the sizes
+    tiler.set_formats(vec![OutputFormat::Webp]);
+    let output_dir = tmp_dir.path().join("webp");
+    let json = tiler.create(&output_dir.to_string_lossy(), "")?;
+    assert_eq!(listed_sizes(&json)?, vec![]);
+    assert!(!output_dir.join("wide/full").exists());
+    assert!(output_dir
+        .join("wide/0,0,1024,8/1024,8/0/default.webp")
+        .exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_streaming_tiff_layouts() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let source = image::RgbImage::from_fn(300, 200, |x, y| {
+        image::Rgb([(x % 256) as u8, (y % 256) as u8, ((x * y) % 256) as u8])
+    });
+
+    // strips of a few rows, and 64x64 tiles padded at the right and bottom edges
+    let striped_path = tmp_dir.path().join("striped.tif");
+    let mut encoder = tiff::encoder::TiffEncoder::new(fs::File::create(&striped_path)?)?;
+    let mut striped = encoder.new_image::<tiff::encoder::colortype::RGB8>(300, 200)?;
+    striped.rows_per_strip(7)?;
+    striped.write_data(source.as_raw())?;
+    let tiled_path = tmp_dir.path().join("tiled.tif");
+    write_tiled_tiff(&tiled_path, &source, 64)?;
+
+    for (name, source_path) in [("striped", &striped_path), ("tiled", &tiled_path)] {
+        let output_dir = tmp_dir.path().join(name);
+        let image_info = ImageInfo::from_dimensions(name, 300, 200, 128, 128, 2);
+        let version = IIIFVersion::VERSION3;
+        let mut tiler = Tiler::new(&image_info, &version);
+        tiler.set_formats(vec![OutputFormat::Png]);
+        tiler.create_streaming(
+            source_path,
+            &output_dir.to_string_lossy(),
+            "http://localhost:8887/iiif/",
+        )?;
+
+        let service_dir = output_dir.join(name);
+        let full = image::open(service_dir.join("full/max/0/default.png"))?.to_rgb8();
+        assert_eq!(full, source, "{}", name);
+        let tile = image::open(service_dir.join("128,128,128,72/128,72/0/default.png"))?.to_rgb8();
+        assert_eq!(
+            tile,
+            image::imageops::crop_imm(&source, 128, 128, 128, 72).to_image(),
+            "{}",
+            name
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_output_formats() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let output_dir = tmp_dir.path().join("iiif");
+
+    let image = IIIFImage::open("tests/fixtures/exact_tiles.jpg")?;
+    let image_info = ImageInfo::new(&image, 1024, 1024, 1);
+    let version = IIIFVersion::VERSION3;
+
+    let mut tiler = Tiler::new(&image_info, &version);
+    tiler.set_formats(vec![
+        OutputFormat::Png,
+        OutputFormat::Jpg,
+        OutputFormat::Webp,
+    ]);
+    let json = tiler.create(&output_dir.to_string_lossy(), "http://localhost:8887/iiif/")?;
+
+    for format in ["png", "jpg", "webp"] {
+        for path in [
+            format!("exact_tiles/0,0,1024,1024/1024,1024/0/default.{}", format),
+            format!("exact_tiles/full/max/0/default.{}", format),
+            format!("exact_tiles/full/1024,1024/0/default.{}", format),
+        ] {
+            let full_path = output_dir.join(path);
+            assert!(
+                full_path.exists(),
+                "Expected {} to exist",
+                full_path.display()
+            );
         }
     }
+    let webp = fs::read(output_dir.join("exact_tiles/full/max/0/default.webp"))?;
+    assert_eq!(image::guess_format(&webp)?, image::ImageFormat::WebP);
 
-    Ok(files)
+    let parsed: Value = serde_json::from_str(&json)?;
+    assert_eq!(
+        parsed["preferredFormats"],
+        serde_json::json!(["png", "jpg", "webp"])
+    );
+    assert_eq!(parsed["extraFormats"], serde_json::json!(["png", "webp"]));
+
+    // v2 lists the extra formats in the profile
+    let version = IIIFVersion::VERSION211;
+    let mut info_json = InfoJSON::new(&image_info, "http://localhost:8887/iiif/", &version);
+    info_json.set_formats(vec![OutputFormat::Jpg, OutputFormat::Png]);
+    let parsed: Value = serde_json::from_str(&info_json.to_json()?)?;
+    assert_eq!(
+        parsed["profile"],
+        serde_json::json!([
+            "http://iiif.io/api/image/2/level0.json",
+            { "formats": ["jpg", "png"], "qualities": ["default"], "supports": [] }
+        ])
+    );
+    assert!(parsed.get("preferredFormats").is_none());
+
+    // level 0 needs jpg, so it's written after the formats asked for
+    let output_dir = tmp_dir.path().join("png");
+    let version = IIIFVersion::VERSION3;
+    let mut tiler = Tiler::new(&image_info, &version);
+    tiler.set_formats(vec![OutputFormat::Png]);
+    let json = tiler.create(&output_dir.to_string_lossy(), "http://localhost:8887/iiif/")?;
+    assert!(output_dir
+        .join("exact_tiles/0,0,1024,1024/1024,1024/0/default.jpg")
+        .exists());
+    let parsed: Value = serde_json::from_str(&json)?;
+    assert_eq!(
+        parsed["preferredFormats"],
+        serde_json::json!(["png", "jpg"])
+    );
+    assert_eq!(parsed["extraFormats"], serde_json::json!(["png"]));
+
+    Ok(())
+}
+
+#[test]
+fn test_encoder_settings() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let image = IIIFImage::open("tests/fixtures/exact_tiles.jpg")?;
+    let image_info = ImageInfo::new(&image, 1024, 1024, 1);
+    let version = IIIFVersion::VERSION3;
+    let formats = vec![OutputFormat::Jpg, OutputFormat::Png, OutputFormat::Webp];
+
+    let write = |name: &str, settings: EncoderSettings| -> Result<(), TilerError> {
+        let mut tiler = Tiler::new(&image_info, &version);
+        tiler.set_formats(formats.clone());
+        tiler.set_encoder_settings(settings);
+        tiler.generate_tiles(&tmp_dir.path().join(name).to_string_lossy())
+    };
+    let read = |name: &str, ext: &str| {
+        fs::read(
+            tmp_dir
+                .path()
+                .join(name)
+                .join("exact_tiles/full/max/0")
+                .join(format!("default.{}", ext)),
+        )
+    };
+
+    write(
+        "low",
+        EncoderSettings {
+            jpeg_quality: 20,
+            png_compression: PngCompression::Fast,
+            webp_quality: cfg!(feature = "lossy-webp").then_some(20),
+            ..EncoderSettings::default()
+        },
+    )?;
+    write(
+        "high",
+        EncoderSettings {
+            jpeg_quality: 95,
+            jpeg_progressive: true,
+            chroma_subsampling: ChromaSubsampling::Cs444,
+            png_compression: PngCompression::Best,
+            webp_quality: None,
+            ..EncoderSettings::default()
+        },
+    )?;
+
+    let (low_jpg, high_jpg) = (read("low", "jpg")?, read("high", "jpg")?);
+    assert!(low_jpg.len() < high_jpg.len());
+    // progressive JPEGs use the SOF2 frame marker, baseline ones SOF0
+    let has_marker = |data: &[u8], marker: u8| data.windows(2).any(|w| w == [0xFF, marker]);
+    assert!(has_marker(&low_jpg, 0xC0) && !has_marker(&low_jpg, 0xC2));
+    assert!(has_marker(&high_jpg, 0xC2));
+    assert_eq!(
+        image::load_from_memory(&high_jpg)?.width() as i32,
+        image.get_width()
+    );
+
+    assert!(read("low", "png")?.len() >= read("high", "png")?.len());
+
+    // lossy WebP is stored in a VP8 chunk, lossless in VP8L
+    #[cfg(feature = "lossy-webp")]
+    assert_eq!(&read("low", "webp")?[12..16], b"VP8 ");
+    assert_eq!(&read("high", "webp")?[12..16], b"VP8L");
+    #[cfg(not(feature = "lossy-webp"))]
+    assert!(write(
+        "lossy",
+        EncoderSettings {
+            webp_quality: Some(20),
+            ..EncoderSettings::default()
+        }
+    )
+    .is_err());
+
+    let result = write(
+        "invalid",
+        EncoderSettings {
+            jpeg_quality: 0,
+            ..EncoderSettings::default()
+        },
+    );
+    assert!(matches!(result, Err(TilerError::InvalidParameter(_))));
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "Invalid parameter: JPEG quality must be between 1 and 100, got 0"
+    );
+    let result = write(
+        "invalid",
+        EncoderSettings {
+            webp_quality: Some(101),
+            ..EncoderSettings::default()
+        },
+    );
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "Invalid parameter: WebP quality must be between 0 and 100, got 101"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_preserve_pixels() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let output_dir = tmp_dir.path().join("iiif");
+
+    // left half transparent, right half opaque blue
+    let overlay = image::RgbaImage::from_fn(64, 32, |x, _| {
+        if x < 32 {
+            image::Rgba([0, 0, 0, 0])
+        } else {
+            image::Rgba([0, 0, 255, 255])
+        }
+    });
+    let overlay_path = tmp_dir.path().join("overlay.png");
+    overlay.save(&overlay_path)?;
+    let archival = image::ImageBuffer::from_fn(64, 32, |x, y| image::Luma([(x * y * 31) as u16]));
+    let archival_path = tmp_dir.path().join("archival.png");
+    image::DynamicImage::ImageLuma16(archival).save(&archival_path)?;
+
+    // the default still converts to 8-bit RGB
+    let image = IIIFImage::open(&overlay_path)?;
+    assert_eq!(image.image().color(), image::ColorType::Rgb8);
+
+    let options = LoadOptions {
+        pixels: PixelMode::Preserve,
+        ..LoadOptions::default()
+    };
+    let version = IIIFVersion::VERSION3;
+    for (path, color) in [
+        (&overlay_path, image::ColorType::Rgba8),
+        (&archival_path, image::ColorType::L16),
+    ] {
+        let image = IIIFImage::open_with_options(path, &options)?;
+        assert_eq!(image.image().color(), color);
+
+        let image_info = ImageInfo::new(&image, 32, 32, 1);
+        let mut tiler = Tiler::new(&image_info, &version);
+        tiler.set_formats(vec![OutputFormat::Png, OutputFormat::Jpg]);
+        tiler.set_encoder_settings(EncoderSettings {
+            background: [255, 0, 0],
+            ..EncoderSettings::default()
+        });
+        tiler.generate_tiles(&output_dir.to_string_lossy())?;
+
+        let tile_dir = output_dir.join(image.id()).join("32,0,32,32/32,32/0");
+        let png = image::open(tile_dir.join("default.png"))?;
+        assert_eq!(png.color(), color);
+        let jpg = image::open(tile_dir.join("default.jpg"))?;
+        assert!(!jpg.color().has_alpha() && jpg.color().bytes_per_pixel() <= 3);
+    }
+
+    // transparent pixels are flattened onto the background in JPEGs
+    let jpg = image::open(output_dir.join("overlay/full/max/0/default.jpg"))?.to_rgb8();
+    let [r, g, b] = jpg.get_pixel(8, 16).0;
+    assert!(r > 240 && g < 16 && b < 16, "got {:?}", (r, g, b));
+    let [r, _, b] = jpg.get_pixel(56, 16).0;
+    assert!(r < 16 && b > 240, "got {:?}", (r, b));
+
+    Ok(())
+}
+
+#[test]
+fn test_qualities() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let output_dir = tmp_dir.path().join("iiif");
+
+    let gradient = image::RgbImage::from_fn(64, 32, |x, y| {
+        image::Rgb([(x * 4) as u8, (y * 8) as u8, 90])
+    });
+    let image = IIIFImage::from_bytes(&encode_png(&gradient)?, "gradient")?;
+    let image_info = ImageInfo::new(&image, 32, 32, 1);
+    let version = IIIFVersion::VERSION3;
+
+    for bitonal in [Bitonal::Threshold(100), Bitonal::Dither] {
+        let mut tiler = Tiler::new(&image_info, &version);
+        tiler.set_formats(vec![OutputFormat::Png, OutputFormat::Jpg]);
+        tiler.set_qualities(vec![Quality::Gray, Quality::Bitonal]);
+        tiler.set_bitonal(bitonal);
+        let json = tiler.create(&output_dir.to_string_lossy(), "http://localhost:8887/iiif/")?;
+
+        for dir in ["0,0,32,32/32,32", "full/max", "full/32,16"] {
+            for file in ["default", "gray", "bitonal"] {
+                for ext in ["png", "jpg"] {
+                    let path = output_dir.join(format!("gradient/{}/0/{}.{}", dir, file, ext));
+                    assert!(path.exists(), "Expected {} to exist", path.display());
+                }
+            }
+        }
+        let gray = image::open(output_dir.join("gradient/full/max/0/gray.png"))?;
+        assert_eq!(gray.color(), image::ColorType::L8);
+        let bitonal_image =
+            image::open(output_dir.join("gradient/full/max/0/bitonal.png"))?.to_luma8();
+        assert!(bitonal_image.pixels().all(|p| p.0[0] == 0 || p.0[0] == 255));
+        assert!(bitonal_image.pixels().any(|p| p.0[0] == 0));
+        assert!(bitonal_image.pixels().any(|p| p.0[0] == 255));
+
+        let parsed: Value = serde_json::from_str(&json)?;
+        assert_eq!(
+            parsed["extraQualities"],
+            serde_json::json!(["gray", "bitonal"])
+        );
+    }
+
+    let version = IIIFVersion::VERSION211;
+    let mut info_json = InfoJSON::new(&image_info, "http://localhost:8887/iiif/", &version);
+    info_json.set_qualities(vec![Quality::Bitonal]);
+    let parsed: Value = serde_json::from_str(&info_json.to_json()?)?;
+    assert_eq!(
+        parsed["profile"],
+        serde_json::json!([
+            "http://iiif.io/api/image/2/level0.json",
+            { "formats": ["jpg"], "qualities": ["default", "bitonal"], "supports": [] }
+        ])
+    );
+    assert!(parsed.get("extraQualities").is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_rotations() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let gradient = image::RgbImage::from_fn(64, 32, |x, y| {
+        image::Rgb([(x * 4) as u8, (y * 8) as u8, 90])
+    });
+    let image = IIIFImage::from_bytes(&encode_png(&gradient)?, "gradient")?;
+    let image_info = ImageInfo::new(&image, 32, 32, 1);
+    let version = IIIFVersion::VERSION3;
+    let rotations = vec![
+        Rotation::Rotate90,
+        Rotation::Rotate180,
+        Rotation::Rotate270,
+        Rotation::Mirror,
+    ];
+
+    for rotate_tiles in [false, true] {
+        let output_dir = tmp_dir.path().join(format!("iiif_{}", rotate_tiles));
+        let mut tiler = Tiler::new(&image_info, &version);
+        tiler.set_formats(vec![OutputFormat::Png]);
+        tiler.set_qualities(vec![Quality::Gray]);
+        tiler.set_rotations(rotations.clone());
+        tiler.set_rotate_tiles(rotate_tiles);
+        let json = tiler.create(&output_dir.to_string_lossy(), "http://localhost:8887/iiif/")?;
+
+        let read = |path: &str| image::open(output_dir.join("gradient").join(path));
+        for size in ["full/max", "full/64,32", "full/32,16"] {
+            for rotation in ["0", "90", "180", "270", "!0"] {
+                for quality in ["default", "gray"] {
+                    read(&format!("{}/{}/{}.png", size, rotation, quality))?;
+                }
+            }
+        }
+        let rotated = read("full/max/90/default.png")?.to_rgb8();
+        assert_eq!(rotated.dimensions(), (32, 64));
+        assert_eq!(rotated.get_pixel(31, 0), gradient.get_pixel(0, 0));
+        let mirrored = read("full/max/!0/default.png")?.to_rgb8();
+        assert_eq!(mirrored.get_pixel(63, 0), gradient.get_pixel(0, 0));
+        assert_eq!(
+            output_dir.join("gradient/32,0,32,32/32,32/90").exists(),
+            rotate_tiles
+        );
+
+        // rotations of the full image and sizes alone aren't advertised
+        let parsed: Value = serde_json::from_str(&json)?;
+        if rotate_tiles {
+            assert_eq!(
+                parsed["extraFeatures"],
+                serde_json::json!(["rotationBy90s", "mirroring"])
+            );
+        } else {
+            assert!(parsed.get("extraFeatures").is_none());
+        }
+    }
+
+    let version = IIIFVersion::VERSION211;
+    let mut info_json = InfoJSON::new(&image_info, "http://localhost:8887/iiif/", &version);
+    info_json.set_rotations(vec![Rotation::Mirror]);
+    let parsed: Value = serde_json::from_str(&info_json.to_json()?)?;
+    assert_eq!(
+        parsed["profile"],
+        serde_json::json!([
+            "http://iiif.io/api/image/2/level0.json",
+            { "formats": ["jpg"], "qualities": ["default"], "supports": ["mirroring"] }
+        ])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_both_versions() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let output_dir = tmp_dir.path().join("iiif");
+    let image = IIIFImage::new("tests/fixtures/odd-sized.jpg");
+    let image_info = ImageInfo::new(&image, 256, 256, 2);
+
+    let version = IIIFVersion::VERSION3;
+    let mut tiler = Tiler::new(&image_info, &version);
+    tiler.set_versions(vec![IIIFVersion::VERSION211, IIIFVersion::VERSION3]);
+    let manifests =
+        tiler.create_all(&output_dir.to_string_lossy(), "http://localhost:8887/iiif/")?;
+
+    // each version has the same files as when it's tiled on its own
+    for (version, manifest) in &manifests {
+        let single_dir = tmp_dir.path().join(version.dir_name());
+        Tiler::new(&image_info, version).generate_tiles(&single_dir.to_string_lossy())?;
+        let service_dir = tiler.service_dir(&output_dir.to_string_lossy(), version);
+        assert_eq!(
+            service_dir,
+            output_dir.join(version.dir_name()).join("odd-sized")
+        );
+        let strip = |files: Vec<String>, prefix: &Path| -> Vec<String> {
+            let mut files: Vec<String> = files
+                .iter()
+                .map(|f| f.replace(&*prefix.to_string_lossy(), ""))
+                .collect();
+            files.sort();
+            files
+        };
+        assert_eq!(
+            strip(count_files(&service_dir)?, &service_dir),
+            strip(
+                count_files(&single_dir.join("odd-sized"))?,
+                &single_dir.join("odd-sized")
+            )
+        );
+
+        let service = ImageService::from_json(manifest)?;
+        assert_eq!(
+            service.id(),
+            format!(
+                "http://localhost:8887/iiif/{}/odd-sized",
+                version.dir_name()
+            )
+        );
+    }
+    assert!(manifests[0].1.contains("@id"));
+
+    // tiles are only encoded once and shared between the layouts
+    let v2_tile = output_dir.join("v2/odd-sized/0,0,256,256/256,/0/default.jpg");
+    let v3_tile = output_dir.join("v3/odd-sized/0,0,256,256/256,256/0/default.jpg");
+    assert_eq!(fs::read(&v2_tile)?, fs::read(&v3_tile)?);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert_eq!(fs::metadata(&v2_tile)?.ino(), fs::metadata(&v3_tile)?.ino());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_presentation_manifest() -> Result<(), Box<dyn std::error::Error>> {
+    let uri = "http://localhost:8887/iiif/";
+    let image = IIIFImage::new("tests/fixtures/odd-sized.jpg");
+    let image_info = ImageInfo::new(&image, 256, 256, 5);
+    let version = IIIFVersion::VERSION3;
+    let mut info = InfoJSON::new(&image_info, uri, &version);
+    info.set_formats(vec![OutputFormat::Png, OutputFormat::Jpg]);
+
+    let mut builder = ManifestBuilder::new(uri, "book");
+    builder.add_canvas("page 1", info.to_service());
+    builder.add_canvas(
+        "page 2",
+        InfoJSON::new(&image_info, uri, &version).to_service(),
+    );
+    let manifest = builder.to_manifest3();
+    assert_eq!(manifest.id, "http://localhost:8887/iiif/manifest.json");
+    assert_eq!(manifest.items.len(), 2);
+
+    let canvas = &manifest.items[0];
+    assert_eq!(canvas.label["none"], vec!["page 1"]);
+    assert_eq!(
+        (canvas.width, canvas.height),
+        (image_info.get_width(), image_info.get_height())
+    );
+    let annotation = &canvas.items[0].items[0];
+    assert_eq!(annotation.motivation, "painting");
+    assert_eq!(annotation.target, canvas.id);
+    // the preferred format is painted
+    assert_eq!(
+        annotation.body.id,
+        "http://localhost:8887/iiif/odd-sized/full/max/0/default.png"
+    );
+    assert_eq!(annotation.body.format, "image/png");
+    assert_eq!(annotation.body.service[0]["id"], info.id());
+
+    // the thumbnail is the smallest size at least 200 pixels wide
+    let thumbnail = &canvas.thumbnail[0];
+    let (width, height) = image_info
+        .get_sizes()
+        .into_iter()
+        .filter(|size| size.0 >= 200)
+        .min()
+        .unwrap();
+    assert_eq!(
+        thumbnail.id,
+        format!("{}/full/{},{}/0/default.png", info.id(), width, height)
+    );
+    assert_eq!(
+        (thumbnail.width, thumbnail.height),
+        (Some(width), Some(height))
+    );
+    assert_eq!(manifest.thumbnail, canvas.thumbnail);
+    assert_ne!(manifest.items[1].id, canvas.id);
+    assert_eq!(
+        manifest.items[1].items[0].items[0].body.format,
+        "image/jpeg"
+    );
+
+    // when the larger sizes weren't written, e.g. streaming within a memory budget, the largest
+    // size that was is painted
+    let sizes = image_info.get_sizes();
+    let mut partial = InfoJSON::new(&image_info, uri, &version);
+    partial.set_sizes(sizes[..3].to_vec());
+    let mut partial_builder = ManifestBuilder::new(uri, "partial");
+    partial_builder.add_canvas("page 1", partial.to_service());
+    let (width, height) = sizes[2];
+    let body = &partial_builder.to_manifest3().items[0].items[0].items[0].body;
+    assert_eq!(
+        body.id,
+        format!("{}/full/{},{}/0/default.jpg", info.id(), width, height)
+    );
+    assert_eq!((body.width, body.height), (Some(width), Some(height)));
+    let resource = &partial_builder.to_manifest2().sequences[0].canvases[0].images[0].resource;
+    assert_eq!(
+        (resource.width, resource.height),
+        (Some(width), Some(height))
+    );
+
+    let json = manifest.to_json_pretty()?;
+    assert_eq!(Manifest3::from_json(&json)?, manifest);
+    let value: Value = serde_json::from_str(&json)?;
+    assert_eq!(
+        value["@context"],
+        "http://iiif.io/api/presentation/3/context.json"
+    );
+    assert_eq!(value["items"][0]["items"][0]["type"], "AnnotationPage");
+
+    Ok(())
+}
+
+#[test]
+fn test_presentation_2_and_collections() -> Result<(), Box<dyn std::error::Error>> {
+    let uri = "http://localhost:8887/iiif/";
+    let image = IIIFImage::new("tests/fixtures/odd-sized.jpg");
+    let image_info = ImageInfo::new(&image, 256, 256, 5);
+    let version = IIIFVersion::VERSION211;
+    let info = InfoJSON::new(&image_info, uri, &version);
+
+    let mut builder = ManifestBuilder::new("http://localhost:8887/iiif/book1/", "book1");
+    builder.add_image("page 1", &info);
+    let manifest = builder.to_manifest2();
+    assert_eq!(
+        manifest.id,
+        "http://localhost:8887/iiif/book1/manifest.json"
+    );
+    assert_eq!(manifest.manifest_type, "sc:Manifest");
+    let canvas = &manifest.sequences[0].canvases[0];
+    assert_eq!(canvas.label, "page 1");
+    assert_eq!(
+        (canvas.width, canvas.height),
+        (image_info.get_width(), image_info.get_height())
+    );
+    let annotation = &canvas.images[0];
+    assert_eq!(annotation.motivation, "sc:painting");
+    assert_eq!(annotation.on, canvas.id);
+    assert_eq!(
+        annotation.resource.id,
+        "http://localhost:8887/iiif/odd-sized/full/full/0/default.jpg"
+    );
+    let service = annotation.resource.service.as_ref().unwrap();
+    assert_eq!(service["@id"], info.id());
+    assert_eq!(
+        service["@context"],
+        "http://iiif.io/api/image/2/context.json"
+    );
+    assert_eq!(manifest.thumbnail, canvas.thumbnail);
+    let json = manifest.to_json()?;
+    assert_eq!(Manifest2::from_json(&json)?, manifest);
+
+    // a collection of a sub-collection and a manifest
+    let mut chapters = CollectionBuilder::new("http://localhost:8887/iiif/chapters/", "chapters");
+    chapters.add_manifest(&builder);
+    let mut collection = CollectionBuilder::new(uri, "library");
+    collection.add_collection(&chapters);
+    collection.add_manifest(&builder);
+
+    let collection2: Value = serde_json::from_str(&collection.to_collection2().to_json()?)?;
+    assert_eq!(collection2["@type"], "sc:Collection");
+    assert_eq!(
+        collection2["@id"],
+        "http://localhost:8887/iiif/collection.json"
+    );
+    assert_eq!(
+        collection2["collections"][0]["@id"],
+        "http://localhost:8887/iiif/chapters/collection.json"
+    );
+    assert_eq!(collection2["manifests"][0]["@id"], manifest.id);
+    assert_eq!(collection2["manifests"][0]["@type"], "sc:Manifest");
+    assert_eq!(collection2["manifests"][0]["label"], "book1");
+
+    let collection3 = collection.to_collection3();
+    assert_eq!(collection3.items.len(), 2);
+    assert_eq!(collection3.items[0].reference_type, "Collection");
+    assert_eq!(collection3.items[1].reference_type, "Manifest");
+    assert_eq!(collection3.items[1].label["none"], vec!["book1"]);
+
+    Ok(())
+}
+
+#[test]
+fn test_nested_id() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let output_dir = tmp_dir.path().to_string_lossy().to_string();
+    let mut image = IIIFImage::open("tests/fixtures/odd-sized.jpg")?;
+    image.set_id("book1/page001");
+    let image_info = ImageInfo::new(&image, 1024, 1024, 2);
+
+    let version = IIIFVersion::VERSION3;
+    let tiler = Tiler::new(&image_info, &version);
+    let manifest = tiler.create(&output_dir, "http://localhost:8887/iiif/")?;
+    assert_eq!(
+        ImageService::from_json(&manifest)?.id(),
+        "http://localhost:8887/iiif/book1%2Fpage001"
+    );
+    assert!(tmp_dir
+        .path()
+        .join("book1/page001/full/max/0/default.jpg")
+        .is_file());
+
+    Ok(())
+}
+
+#[test]
+fn test_identifier_strategies() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let folder = tmp_dir.path().join("book 1");
+    fs::create_dir_all(&folder)?;
+    let page = folder.join("page1.jpg");
+    fs::copy("tests/fixtures/odd-sized.jpg", &page)?;
+    let copy = tmp_dir.path().join("copy.jpg");
+    fs::copy("tests/fixtures/odd-sized.jpg", &copy)?;
+
+    assert_eq!(IdentifierStrategy::FileStem.identify(&page)?, "page1");
+    let relative = |separator: &str| IdentifierStrategy::RelativePath {
+        root: tmp_dir.path().to_path_buf(),
+        separator: separator.to_string(),
+    };
+    assert_eq!(relative("/").identify(&page)?, "book 1/page1");
+    assert_eq!(relative("_").identify(&page)?, "book 1_page1");
+    assert_eq!(relative("_").identify(&copy)?, "copy");
+
+    // identical files share a hash
+    let hash = IdentifierStrategy::ContentHash.identify(&page)?;
+    assert_eq!(hash.len(), 16);
+    assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+    assert_eq!(IdentifierStrategy::ContentHash.identify(&copy)?, hash);
+
+    let template = IdentifierStrategy::Template("{parent}_{stem}.{ext}".to_string());
+    assert_eq!(template.identify(&page)?, "book 1_page1.jpg");
+    let template = IdentifierStrategy::Template("{stem}-{hash}".to_string());
+    assert_eq!(template.identify(&page)?, format!("page1-{}", hash));
+    let template = IdentifierStrategy::Template("{folder}_{stem}".to_string());
+    assert!(matches!(
+        template.identify(&page),
+        Err(TilerError::InvalidParameter(_))
+    ));
+
+    let csv_path = tmp_dir.path().join("ids.csv");
+    fs::write(
+        &csv_path,
+        format!(
+            "file,id\n{},first-page\ncopy.jpg, copy-id\n",
+            page.display()
+        ),
+    )?;
+    let mapping = IdentifierStrategy::from_csv(&csv_path)?;
+    assert_eq!(mapping.identify(&page)?, "first-page");
+    assert_eq!(mapping.identify(&copy)?, "copy-id");
+    assert!(matches!(
+        mapping.identify(&tmp_dir.path().join("missing.jpg")),
+        Err(TilerError::InvalidParameter(_))
+    ));
+    fs::write(&csv_path, "copy.jpg\n")?;
+    assert!(IdentifierStrategy::from_csv(&csv_path).is_err());
+
+    // opening with a strategy sets the identifier, which is percent-encoded in the info.json
+    let options = LoadOptions {
+        identifier: relative("/"),
+        ..LoadOptions::default()
+    };
+    let image = IIIFImage::open_with_options(&page, &options)?;
+    assert_eq!(image.id(), "book 1/page1");
+    let image_info = ImageInfo::new(&image, 1024, 1024, 2);
+    let version = IIIFVersion::VERSION3;
+    let info = InfoJSON::new(&image_info, "http://localhost:8887/iiif/", &version);
+    // the files are nested, but the identifier's `/` is encoded in the service's URI
+    assert_eq!(info.id(), "http://localhost:8887/iiif/book%201%2Fpage1");
+    assert_eq!(encode_id("café #1"), "caf%C3%A9%20%231");
+    assert_eq!(encode_id("a/b"), "a%2Fb");
+    let output_dir = tmp_dir.path().join("iiif");
+    Tiler::new(&image_info, &version)
+        .create(&output_dir.to_string_lossy(), "http://localhost:8887/iiif/")?;
+    assert!(output_dir
+        .join("book 1/page1/full/max/0/default.jpg")
+        .exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_exif_orientation() -> Result<(), Box<dyn std::error::Error>> {
+    // 40x20, red on the left and blue on the right, stored with orientation 6 (rotate 90 clockwise)
+    let stored = image::RgbImage::from_fn(40, 20, |x, _| {
+        if x < 20 {
+            image::Rgb([255, 0, 0])
+        } else {
+            image::Rgb([0, 0, 255])
+        }
+    });
+    let mut exif = b"Exif\0\0II*\0\x08\0\0\0\x01\0".to_vec();
+    exif.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0]);
+    let mut bytes = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut bytes, 100);
+    encoder.add_app_segment(1, &exif)?;
+    encoder.encode(stored.as_raw(), 40, 20, jpeg_encoder::ColorType::Rgb)?;
+
+    let image = IIIFImage::from_bytes(&bytes, "rotated")?;
+    assert_eq!(image.orientation(), Orientation::Rotate90);
+    assert_eq!((image.get_width(), image.get_height()), (20, 40));
+    // the left of the stored image is now the top
+    let rgb = image.image().to_rgb8();
+    assert!(rgb.get_pixel(10, 5)[0] > 200 && rgb.get_pixel(10, 5)[2] < 50);
+    assert!(rgb.get_pixel(10, 35)[2] > 200 && rgb.get_pixel(10, 35)[0] < 50);
+
+    let options = LoadOptions {
+        orientation: OrientationMode::Ignore,
+        ..LoadOptions::default()
+    };
+    let image =
+        IIIFImage::from_reader_with_options(std::io::Cursor::new(&bytes), "stored", &options)?;
+    assert_eq!(image.orientation(), Orientation::Rotate90);
+    assert_eq!((image.get_width(), image.get_height()), (40, 20));
+
+    let tmp_dir = TempDir::new()?;
+    let path = tmp_dir.path().join("rotated.jpg");
+    fs::write(&path, &bytes)?;
+    assert_eq!(read_orientation(&path)?, Orientation::Rotate90);
+    assert_eq!(
+        read_orientation("tests/fixtures/odd-sized.jpg")?,
+        Orientation::NoTransforms
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_icc_profiles() -> Result<(), Box<dyn std::error::Error>> {
+    use image::ImageDecoder;
+    use image::ImageEncoder;
+
+    // a Display P3 orange
+    let profile = moxcms::ColorProfile::new_display_p3().encode()?;
+    let source = image::RgbImage::from_pixel(64, 48, image::Rgb([200, 100, 50]));
+    let mut bytes = Vec::new();
+    let mut encoder = image::codecs::png::PngEncoder::new(&mut bytes);
+    encoder.set_icc_profile(profile.clone())?;
+    encoder.write_image(source.as_raw(), 64, 48, image::ExtendedColorType::Rgb8)?;
+
+    // the profile is kept and the pixels are left alone by default
+    let image = IIIFImage::from_bytes(&bytes, "p3")?;
+    assert_eq!(image.icc_profile(), Some(profile.as_slice()));
+    assert_eq!(image.image().to_rgb8().get_pixel(0, 0).0, [200, 100, 50]);
+
+    // converted, the same colour is more saturated in sRGB
+    let options = LoadOptions {
+        icc: IccMode::ConvertToSrgb,
+        ..LoadOptions::default()
+    };
+    let converted =
+        IIIFImage::from_reader_with_options(std::io::Cursor::new(&bytes), "srgb", &options)?;
+    let [r, g, b] = converted.image().to_rgb8().get_pixel(0, 0).0;
+    assert!(r > 200 && g < 100 && b < 50, "got {:?}", [r, g, b]);
+    assert_eq!(converted.icc_profile(), Some(profile.as_slice()));
+
+    let tmp_dir = TempDir::new()?;
+    let path = tmp_dir.path().join("p3.png");
+    fs::write(&path, &bytes)?;
+    assert_eq!(read_icc_profile(&path)?, Some(profile.clone()));
+    assert_eq!(read_icc_profile("tests/fixtures/odd-sized.jpg")?, None);
+
+    // embedded in every format that can hold it
+    let output_dir = tmp_dir.path().join("iiif");
+    let image_info = ImageInfo::new(&image, 1024, 1024, 1);
+    let version = IIIFVersion::VERSION3;
+    let mut tiler = Tiler::new(&image_info, &version);
+    tiler.set_formats(vec![
+        OutputFormat::Jpg,
+        OutputFormat::Png,
+        OutputFormat::Webp,
+    ]);
+    tiler.set_icc_profile(image.icc_profile().map(<[u8]>::to_vec));
+    tiler.generate_tiles(&output_dir.to_string_lossy())?;
+    for extension in ["jpg", "png", "webp"] {
+        let written = output_dir.join(format!("p3/full/max/0/default.{}", extension));
+        let mut decoder = image::ImageReader::open(&written)?
+            .with_guessed_format()?
+            .into_decoder()?;
+        assert_eq!(
+            decoder.icc_profile()?,
+            Some(profile.clone()),
+            "{}",
+            extension
+        );
+    }
+
+    // and in lossy WebP, with and without alpha
+    #[cfg(feature = "lossy-webp")]
+    for pixels in [PixelMode::Rgb8, PixelMode::Preserve] {
+        let source = image::RgbaImage::from_pixel(64, 48, image::Rgba([200, 100, 50, 128]));
+        let mut bytes = Vec::new();
+        source.write_to(
+            &mut std::io::Cursor::new(&mut bytes),
+            image::ImageFormat::Png,
+        )?;
+        let options = LoadOptions {
+            pixels,
+            ..LoadOptions::default()
+        };
+        let image =
+            IIIFImage::from_reader_with_options(std::io::Cursor::new(&bytes), "lossy", &options)?;
+        let output_dir = tmp_dir.path().join(format!("lossy_{:?}", pixels));
+        let image_info = ImageInfo::new(&image, 1024, 1024, 1);
+        let mut tiler = Tiler::new(&image_info, &version);
+        tiler.set_formats(vec![OutputFormat::Webp]);
+        tiler.set_encoder_settings(EncoderSettings {
+            webp_quality: Some(80),
+            ..EncoderSettings::default()
+        });
+        tiler.set_icc_profile(Some(profile.clone()));
+        tiler.generate_tiles(&output_dir.to_string_lossy())?;
+        let written = output_dir.join("lossy/full/max/0/default.webp");
+        let mut decoder = image::codecs::webp::WebPDecoder::new(std::io::BufReader::new(
+            fs::File::open(&written)?,
+        ))?;
+        assert_eq!(
+            decoder.icc_profile()?,
+            Some(profile.clone()),
+            "{:?}",
+            pixels
+        );
+        assert_eq!(decoder.dimensions(), (64, 48));
+        let decoded = image::open(&written)?;
+        assert_eq!(decoded.color().has_alpha(), pixels == PixelMode::Preserve);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_icc_profile_colour_spaces() -> Result<(), Box<dyn std::error::Error>> {
+    use image::ImageDecoder;
+    use moxcms::{ColorProfile, DataColorSpace};
+
+    let tmp_dir = TempDir::new()?;
+    let colour = image::RgbImage::from_pixel(64, 48, image::Rgb([200, 100, 50]));
+    let gray = image::GrayImage::from_pixel(64, 48, image::Luma([120]));
+    let cases = [
+        // an RGB profile with grey and bitonal qualities, which are written as grey pixels
+        (
+            "colour",
+            image::DynamicImage::ImageRgb8(colour),
+            PixelMode::Preserve,
+            ColorProfile::new_display_p3().encode()?,
+        ),
+        // a grey profile on grey pixels promoted to RGB
+        (
+            "gray",
+            image::DynamicImage::ImageLuma8(gray),
+            PixelMode::Rgb8,
+            ColorProfile::new_gray_with_gamma(1.8).encode()?,
+        ),
+    ];
+    let version = IIIFVersion::VERSION3;
+    for (id, source, pixels, profile) in cases {
+        let mut bytes = Vec::new();
+        source.write_to(
+            &mut std::io::Cursor::new(&mut bytes),
+            image::ImageFormat::Png,
+        )?;
+        let options = LoadOptions {
+            pixels,
+            ..LoadOptions::default()
+        };
+        let image =
+            IIIFImage::from_reader_with_options(std::io::Cursor::new(&bytes), id, &options)?;
+        let output_dir = tmp_dir.path().join(id);
+        let image_info = ImageInfo::new(&image, 1024, 1024, 1);
+        let mut tiler = Tiler::new(&image_info, &version);
+        tiler.set_formats(vec![
+            OutputFormat::Jpg,
+            OutputFormat::Png,
+            OutputFormat::Webp,
+        ]);
+        tiler.set_qualities(vec![Quality::Gray, Quality::Bitonal]);
+        tiler.set_icc_profile(Some(profile.clone()));
+        tiler.generate_tiles(&output_dir.to_string_lossy())?;
+
+        for quality in ["default", "gray", "bitonal"] {
+            for extension in ["jpg", "png", "webp"] {
+                let written =
+                    output_dir.join(format!("{}/full/max/0/{}.{}", id, quality, extension));
+                let mut decoder = image::ImageReader::open(&written)?
+                    .with_guessed_format()?
+                    .into_decoder()?;
+                let embedded = decoder.icc_profile()?.expect("a profile");
+                let expected = if decoder.color_type().has_color() {
+                    DataColorSpace::Rgb
+                } else {
+                    DataColorSpace::Gray
+                };
+                assert_eq!(
+                    ColorProfile::new_from_slice(&embedded)?.color_space,
+                    expected,
+                    "{}",
+                    written.display()
+                );
+                // the source's own profile is kept wherever it fits
+                assert_eq!(
+                    embedded == profile,
+                    (id == "colour") == (expected == DataColorSpace::Rgb),
+                    "{}",
+                    written.display()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(all(feature = "bmp", feature = "pnm", feature = "qoi", feature = "tga"))]
+fn test_input_formats() -> Result<(), Box<dyn std::error::Error>> {
+    use iiif_tiler_rust::iiif_image::detect_format;
+    use image::ImageFormat;
+
+    let tmp_dir = TempDir::new()?;
+    let source =
+        image::RgbImage::from_fn(40, 30, |x, y| image::Rgb([x as u8 * 6, y as u8 * 8, 90]));
+    let formats = [
+        ("webp", ImageFormat::WebP),
+        ("gif", ImageFormat::Gif),
+        ("bmp", ImageFormat::Bmp),
+        ("tga", ImageFormat::Tga),
+        ("ppm", ImageFormat::Pnm),
+        ("qoi", ImageFormat::Qoi),
+        ("tif", ImageFormat::Tiff),
+    ];
+    for (extension, format) in formats {
+        let path = tmp_dir.path().join(format!("source.{}", extension));
+        source.save_with_format(&path, format)?;
+        assert_eq!(detect_format(&path), Some(format), "{}", extension);
+
+        let image = IIIFImage::open(&path)?;
+        assert_eq!(
+            (image.get_width(), image.get_height()),
+            (40, 30),
+            "{}",
+            extension
+        );
+    }
+
+    // the contents decide, whatever the extension says
+    let misnamed = tmp_dir.path().join("misnamed.jpg");
+    source.save_with_format(&misnamed, ImageFormat::Png)?;
+    assert_eq!(detect_format(&misnamed), Some(ImageFormat::Png));
+    let image = IIIFImage::open(&misnamed)?;
+    assert_eq!(image.image().to_rgb8(), source);
+
+    let text = tmp_dir.path().join("notes.txt");
+    fs::write(&text, "not an image")?;
+    assert_eq!(detect_format(&text), None);
+    // only TGA goes by its extension
+    let corrupt = tmp_dir.path().join("corrupt.png");
+    fs::write(&corrupt, "not an image")?;
+    assert_eq!(detect_format(&corrupt), None);
+    assert!(IIIFImage::open(&corrupt).is_err());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(all(feature = "hdr", feature = "exr"))]
+fn test_tone_mapping() -> Result<(), Box<dyn std::error::Error>> {
+    use iiif_tiler_rust::iiif_image::ToneMapping;
+    use image::ImageFormat;
+
+    // a dim scene with a highlight eight times as bright as a lamp
+    let source = image::Rgb32FImage::from_fn(32, 16, |x, _| match x {
+        0..=7 => image::Rgb([0.05, 0.05, 0.05]),
+        8..=15 => image::Rgb([0.2, 0.1, 0.05]),
+        16..=23 => image::Rgb([2.0, 2.0, 2.0]),
+        _ => image::Rgb([16.0, 16.0, 16.0]),
+    });
+    let tmp_dir = TempDir::new()?;
+    for (extension, format) in [("hdr", ImageFormat::Hdr), ("exr", ImageFormat::OpenExr)] {
+        let path = tmp_dir.path().join(format!("scene.{}", extension));
+        image::DynamicImage::ImageRgb32F(source.clone()).save_with_format(&path, format)?;
+
+        // the highlights are compressed rather than clipped, keeping them apart
+        let image = IIIFImage::open(&path)?;
+        let pixels = image.image().to_rgb8();
+        let [shadow, colour, lamp, highlight] = [0, 8, 16, 24].map(|x| pixels.get_pixel(x, 0).0);
+        assert!(
+            shadow[0] > 0 && shadow[0] < lamp[0],
+            "{}: {:?}",
+            extension,
+            shadow
+        );
+        assert!(
+            colour[0] > colour[1] && colour[1] > colour[2],
+            "{}: {:?}",
+            extension,
+            colour
+        );
+        assert!(
+            lamp[0] < highlight[0] && highlight[0] < 255,
+            "{}: {:?}",
+            extension,
+            highlight
+        );
+
+        // clipping keeps values up to 1 and loses the highlights
+        let options = LoadOptions {
+            tone_mapping: ToneMapping::Clip,
+            ..LoadOptions::default()
+        };
+        let clipped = IIIFImage::open_with_options(&path, &options)?;
+        let pixels = clipped.image().to_rgb8();
+        assert_eq!(pixels.get_pixel(16, 0), pixels.get_pixel(24, 0));
+        assert_eq!(pixels.get_pixel(16, 0).0, [255, 255, 255]);
+
+        // floating point pixels are never kept, as no output format can hold them
+        let options = LoadOptions {
+            pixels: PixelMode::Preserve,
+            ..LoadOptions::default()
+        };
+        let preserved = IIIFImage::open_with_options(&path, &options)?;
+        assert_eq!(preserved.image().color(), image::ColorType::Rgb16);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_pages() -> Result<(), Box<dyn std::error::Error>> {
+    use iiif_tiler_rust::iiif_image::{page_id, read_page_dimensions};
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, Frame, RgbaImage};
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    let tmp_dir = TempDir::new()?;
+    assert_eq!(page_id("scan", 1), "scan_p0001");
+    assert_eq!(page_id("book1/scan", 12), "book1/scan_p0012");
+
+    // a pamphlet whose pages differ in size and colour
+    let tiff_path = tmp_dir.path().join("pamphlet.tif");
+    let mut encoder = TiffEncoder::new(fs::File::create(&tiff_path)?)?;
+    encoder.write_image::<colortype::RGB8>(40, 30, &[200; 40 * 30 * 3])?;
+    encoder.write_image::<colortype::Gray8>(30, 40, &[50; 30 * 40])?;
+    encoder.write_image::<colortype::RGB16>(20, 10, &[u16::MAX; 20 * 10 * 3])?;
+    drop(encoder);
+
+    let pages = IIIFImage::open_pages(&tiff_path, &LoadOptions::default())?
+        .collect::<Result<Vec<_>, _>>()?;
+    let summary: Vec<_> = pages
+        .iter()
+        .map(|page| (page.id(), page.get_width(), page.get_height()))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("pamphlet_p0001".to_string(), 40, 30),
+            ("pamphlet_p0002".to_string(), 30, 40),
+            ("pamphlet_p0003".to_string(), 20, 10),
+        ]
+    );
+    assert_eq!(pages[1].image().to_rgb8().get_pixel(0, 0).0, [50, 50, 50]);
+    assert_eq!(
+        pages[2].image().to_rgb8().get_pixel(0, 0).0,
+        [255, 255, 255]
+    );
+    // the first page is the one a plain load gives
+    assert_eq!(
+        IIIFImage::open(&tiff_path)?.image().to_rgb8(),
+        pages[0].image().to_rgb8()
+    );
+
+    // frames are composited onto the whole canvas
+    let gif_path = tmp_dir.path().join("animation.gif");
+    let mut encoder = GifEncoder::new(fs::File::create(&gif_path)?);
+    for shade in [0, 128] {
+        let frame = RgbaImage::from_pixel(16, 8, image::Rgba([shade, 0, 255 - shade, 255]));
+        encoder.encode_frame(Frame::from_parts(
+            frame,
+            0,
+            0,
+            Delay::from_numer_denom_ms(100, 1),
+        ))?;
+    }
+    drop(encoder);
+    let mut frames = IIIFImage::open_pages(&gif_path, &LoadOptions::default())?;
+    frames.set_id("movie");
+    let frames = frames.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[1].id(), "movie_p0002");
+    assert_eq!((frames[1].get_width(), frames[1].get_height()), (16, 8));
+    let [r, _, b] = frames[1].image().to_rgb8().get_pixel(0, 0).0;
+    assert!(r > 100 && b < 150, "got {:?}", [r, b]);
+
+    // images with one page keep their identifier
+    let single_path = tmp_dir.path().join("single.tif");
+    image::RgbImage::new(8, 8).save(&single_path)?;
+    let single: Vec<_> = IIIFImage::open_pages(&single_path, &LoadOptions::default())?
+        .map(|page| page.map(|page| page.id()))
+        .collect::<Result<_, _>>()?;
+    assert_eq!(single, vec!["single".to_string()]);
+    let jpeg: Vec<_> =
+        IIIFImage::open_pages("tests/fixtures/odd-sized.jpg", &LoadOptions::default())?
+            .map(|page| page.map(|page| page.id()))
+            .collect::<Result<_, _>>()?;
+    assert_eq!(jpeg, vec!["odd-sized".to_string()]);
+
+    // the pages can be measured without decoding them, e.g. for estimates
+    assert_eq!(
+        read_page_dimensions(&tiff_path, OrientationMode::Apply)?,
+        vec![(40, 30), (30, 40), (20, 10)]
+    );
+    assert_eq!(
+        read_page_dimensions(&gif_path, OrientationMode::Apply)?,
+        vec![(16, 8), (16, 8)]
+    );
+    assert_eq!(
+        read_page_dimensions("tests/fixtures/odd-sized.jpg", OrientationMode::Apply)?,
+        vec![image::image_dimensions("tests/fixtures/odd-sized.jpg")?]
+    );
+
+    // each page is tiled as its own image
+    let output_dir = tmp_dir.path().join("iiif");
+    for page in &pages {
+        let image_info = ImageInfo::new(page, 1024, 1024, 1);
+        let version = IIIFVersion::VERSION3;
+        let tiler = Tiler::new(&image_info, &version);
+        tiler.generate_tiles(&output_dir.to_string_lossy())?;
+    }
+    for id in ["pamphlet_p0001", "pamphlet_p0002", "pamphlet_p0003"] {
+        assert!(
+            output_dir.join(id).join("full/max/0/default.jpg").is_file(),
+            "{}",
+            id
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_tiff_page_colour_types() -> Result<(), Box<dyn std::error::Error>> {
+    use tiff::encoder::colortype::ColorType;
+    use tiff::encoder::{compression, TiffEncoder};
+    use tiff::tags::{PhotometricInterpretation, SampleFormat, Tag};
+
+    struct Palette8;
+    impl ColorType for Palette8 {
+        type Inner = u8;
+        const TIFF_VALUE: PhotometricInterpretation = PhotometricInterpretation::RGBPalette;
+        const BITS_PER_SAMPLE: &'static [u16] = &[8];
+        const SAMPLE_FORMAT: &'static [SampleFormat] = &[SampleFormat::Uint];
+    }
+    struct WhiteIsZero8;
+    impl ColorType for WhiteIsZero8 {
+        type Inner = u8;
+        const TIFF_VALUE: PhotometricInterpretation = PhotometricInterpretation::WhiteIsZero;
+        const BITS_PER_SAMPLE: &'static [u16] = &[8];
+        const SAMPLE_FORMAT: &'static [SampleFormat] = &[SampleFormat::Uint];
+    }
+
+    // a compressed palette page, then a page where 0 is white
+    let tmp_dir = TempDir::new()?;
+    let tiff_path = tmp_dir.path().join("scan.tif");
+    let mut encoder = TiffEncoder::new(fs::File::create(&tiff_path)?)?;
+    let mut color_map = vec![0u16; 3 * 256];
+    for (index, [r, g, b]) in [[255, 0, 0], [0, 128, 0], [10, 20, 30]].iter().enumerate() {
+        color_map[index] = r * 257;
+        color_map[256 + index] = g * 257;
+        color_map[512 + index] = b * 257;
+    }
+    let mut palette = encoder.new_image_with_compression::<Palette8, _>(3, 2, compression::Lzw)?;
+    palette.encoder().write_tag(Tag::ColorMap, &color_map[..])?;
+    palette.write_data(&[0, 1, 2, 2, 1, 0])?;
+    encoder.write_image::<WhiteIsZero8>(2, 1, &[0, 255])?;
+    // and a palette page further in, found by walking the directories
+    let mut later = encoder.new_image::<Palette8>(2, 1)?;
+    later.encoder().write_tag(Tag::ColorMap, &color_map[..])?;
+    later.write_data(&[2, 1])?;
+
+    let pages = IIIFImage::open_pages(&tiff_path, &LoadOptions::default())?
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(pages.len(), 3);
+    let palette = pages[0].image().to_rgb8();
+    assert_eq!(palette.dimensions(), (3, 2));
+    assert_eq!(palette.get_pixel(0, 0).0, [255, 0, 0]);
+    assert_eq!(palette.get_pixel(1, 0).0, [0, 128, 0]);
+    assert_eq!(palette.get_pixel(0, 1).0, [10, 20, 30]);
+    assert_eq!(palette.get_pixel(2, 1).0, [255, 0, 0]);
+    let inverted = pages[1].image().to_rgb8();
+    assert_eq!(inverted.get_pixel(0, 0).0, [255, 255, 255]);
+    assert_eq!(inverted.get_pixel(1, 0).0, [0, 0, 0]);
+    let later = pages[2].image().to_rgb8();
+    assert_eq!(later.get_pixel(0, 0).0, [10, 20, 30]);
+    assert_eq!(later.get_pixel(1, 0).0, [0, 128, 0]);
+
+    // image can't decode palette colours itself, so a plain load falls back to the first page
+    let first = IIIFImage::open(&tiff_path)?;
+    assert_eq!(first.id(), "scan");
+    assert_eq!(first.image().to_rgb8(), palette);
+
+    Ok(())
+}
+
+#[test]
+fn test_max_files() -> Result<(), Box<dyn std::error::Error>> {
+    use iiif_tiler_rust::iiif_image::read_dimensions;
+
+    // no tile size and zoom level get a 2048 pixel square into 20 files
+    let mut image_info = ImageInfo::from_dimensions("square", 2048, 2048, 256, 256, 4);
+    match image_info.try_fit_to_max_file_no(20) {
+        Err(TilerError::InvalidParameter(message)) => {
+            assert!(message.contains("the fewest is 32"), "{}", message)
+        }
+        other => panic!("expected the fit to fail, got {:?}", other),
+    }
+    assert_eq!(image_info.get_tile_width(), 256);
+    assert_eq!(image_info.get_scale_factors().len(), 5);
+
+    // without the error, the combination with the fewest files is used instead of looping forever
+    image_info.fit_to_max_file_no(20);
+    assert_eq!(image_info.calculate_file_count(), 32);
+
+    let file_count = image_info.try_fit_to_max_file_no(100)?;
+    assert_eq!(file_count, image_info.calculate_file_count());
+    assert!(file_count < 100);
+
+    // too large for the smallest zoom level to fit in one tile, so counted tile by tile
+    let mut huge = ImageInfo::from_dimensions("huge", 100_000, 1000, 256, 256, 4);
+    let file_count = huge.try_fit_to_max_file_no(1_000_000)?;
+    assert!(file_count < 1_000_000);
+    assert_eq!(huge.get_tile_width(), 256);
+    assert!(huge.try_fit_to_max_file_no(100).is_err());
+
+    // which is the number of files written
+    let tmp_dir = TempDir::new()?;
+    let wide = image::RgbImage::from_pixel(5000, 700, image::Rgb([90, 120, 200]));
+    let image = IIIFImage::from_bytes(&encode_png(&wide)?, "wide")?;
+    let mut image_info = ImageInfo::new(&image, 256, 256, 4);
+    let file_count = image_info.try_fit_to_max_file_no(400)?;
+    assert_eq!(
+        (
+            image_info.get_tile_width(),
+            image_info.get_scale_factors().len()
+        ),
+        (256, 5)
+    );
+    let version = IIIFVersion::VERSION3;
+    Tiler::new(&image_info, &version).generate_tiles(&tmp_dir.path().to_string_lossy())?;
+    assert_eq!(file_count, image_info.calculate_file_count());
+    assert_eq!(count_files(tmp_dir.path())?.len() as i32 + 1, file_count);
+
+    // every tile level plus every size
+    let image_info = ImageInfo::from_dimensions("small", 100, 60, 256, 256, 1);
+    assert_eq!(image_info.calculate_pixel_count(), 2 * (100 * 60 + 50 * 30));
+
+    assert_eq!(
+        read_dimensions("tests/fixtures/exact_tiles.jpg", OrientationMode::Apply)?,
+        (2048, 2048)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_estimate_matches_run() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let source = tmp_dir.path().join("cover.png");
+    image::RgbImage::from_pixel(1537, 1537, image::Rgb([40, 80, 120])).save(&source)?;
+    let source = source.to_string_lossy().into_owned();
+    let options = ["-t", "256", "-z", "2", "--max-files", "100"];
+
+    let estimate = run_cli(&[&["estimate", &source][..], &options].concat())?;
+    assert!(estimate.status.success());
+    let total = String::from_utf8(estimate.stdout)?
+        .lines()
+        .find_map(|line| line.strip_prefix("Total: "))
+        .and_then(|line| line.split(' ').next())
+        .expect("a total line")
+        .parse::<usize>()?;
+
+    let output_dir = tmp_dir.path().join("iiif").to_string_lossy().into_owned();
+    let run = run_cli(&[&[&source, "-o", &output_dir][..], &options].concat())?;
+    assert!(run.status.success());
+    // the info.json is written too
+    assert_eq!(count_files(Path::new(&output_dir))?.len(), total);
+    assert!(total < 100);
+
+    Ok(())
+}
+
+#[test]
+fn test_bad_image_in_directory_fails() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::new()?;
+    let folder = tmp_dir.path().join("images");
+    fs::create_dir_all(&folder)?;
+    image::RgbImage::from_pixel(64, 48, image::Rgb([10, 20, 30])).save(folder.join("a.png"))?;
+    // cut off before the image's contents can be recognised
+    fs::write(folder.join("c.jpg"), b"\xFF")?;
+    fs::write(folder.join("notes.txt"), "not an image")?;
+
+    let output_dir = tmp_dir.path().join("iiif");
+    let run = run_cli(&[
+        &folder.to_string_lossy(),
+        "-o",
+        &output_dir.to_string_lossy(),
+    ])?;
+    assert!(!run.status.success());
+    let stderr = String::from_utf8(run.stderr)?;
+    assert!(stderr.contains("c.jpg"), "{}", stderr);
+    assert!(!stderr.contains("notes.txt"), "{}", stderr);
+    assert!(output_dir.join("a/info.json").exists());
+
+    Ok(())
+}
+
+//Helper functions for tests
+
+/// Recursively count files in a directory
+fn count_files(directory: &Path) -> Result<Vec<String>, std::io::Error> {
+    let mut files = Vec::new();
+
+    // Optional debug output
+    // if fs::read_dir(directory)?.count() > 1 {
+    //     println!("Multiple {}", directory.display());
+    // }
+
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        if let Some(path_str) = path.to_str() {
+            files.push(path_str.to_string());
+        }
+
+        if path.is_dir() {
+            let mut sub_files = count_files(&path)?;
+            files.append(&mut sub_files);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Checks every image is exactly the size its `.../<size>/<rotation>/default.jpg` path asks for
+fn assert_sizes_match_paths(files: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    for file in files.iter().filter(|f| f.ends_with(".jpg")) {
+        let size = Path::new(file)
+            .ancestors()
+            .nth(2)
+            .and_then(|size_dir| size_dir.file_name())
+            .and_then(|size_dir| size_dir.to_str())
+            .unwrap();
+        let dimensions = image::image_dimensions(file)?;
+        match size.split_once(',') {
+            Some((width, "")) => assert_eq!(width.parse::<u32>()?, dimensions.0),
+            Some((width, height)) => assert_eq!(
+                (width.parse::<u32>()?, height.parse::<u32>()?),
+                dimensions,
+                "{} is the wrong size",
+                file
+            ),
+            None => assert!(size == "max" || size == "full"),
+        }
+    }
+    Ok(())
+}
+
+/// The width and height of each size listed in an info.json
+fn listed_sizes(json: &str) -> Result<Vec<(i64, i64)>, serde_json::Error> {
+    let info: Value = serde_json::from_str(json)?;
+    Ok(info["sizes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|size| {
+            (
+                size["width"].as_i64().unwrap(),
+                size["height"].as_i64().unwrap(),
+            )
+        })
+        .collect())
+}
+
+/// Encodes an image as PNG in memory
+fn encode_png(image: &image::RgbImage) -> Result<Vec<u8>, image::ImageError> {
+    let mut bytes = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut bytes, image::ImageFormat::Png)?;
+    Ok(bytes.into_inner())
+}
+
+// writes an uncompressed RGB8 TIFF in square tiles, which the tiff crate can't encode
+fn write_tiled_tiff(path: &Path, image: &image::RgbImage, tile: u32) -> Result<(), std::io::Error> {
+    let (width, height) = image.dimensions();
+    let (across, down) = (width.div_ceil(tile), height.div_ceil(tile));
+    let tile_bytes = tile * tile * 3;
+    let tile_count = across * down;
+    // the header, then eleven directory entries, then the bits per sample and tile offset arrays
+    let bits_offset = 8 + 2 + 11 * 12 + 4;
+    let offsets_offset = bits_offset + 6;
+    let counts_offset = offsets_offset + 4 * tile_count;
+    let data_offset = counts_offset + 4 * tile_count;
+
+    let mut tiff = b"II*\0".to_vec();
+    tiff.extend_from_slice(&8u32.to_le_bytes());
+    tiff.extend_from_slice(&11u16.to_le_bytes());
+    let entries: [(u16, u16, u32, u32); 11] = [
+        (256, 4, 1, width),
+        (257, 4, 1, height),
+        (258, 3, 3, bits_offset),
+        (259, 3, 1, 1),
+        (262, 3, 1, 2),
+        (277, 3, 1, 3),
+        (284, 3, 1, 1),
+        (322, 4, 1, tile),
+        (323, 4, 1, tile),
+        (324, 4, tile_count, offsets_offset),
+        (325, 4, tile_count, counts_offset),
+    ];
+    for (tag, field_type, count, value) in entries {
+        tiff.extend_from_slice(&tag.to_le_bytes());
+        tiff.extend_from_slice(&field_type.to_le_bytes());
+        tiff.extend_from_slice(&count.to_le_bytes());
+        tiff.extend_from_slice(&value.to_le_bytes());
+    }
+    tiff.extend_from_slice(&0u32.to_le_bytes());
+    for _ in 0..3 {
+        tiff.extend_from_slice(&8u16.to_le_bytes());
+    }
+    for index in 0..tile_count {
+        tiff.extend_from_slice(&(data_offset + index * tile_bytes).to_le_bytes());
+    }
+    for _ in 0..tile_count {
+        tiff.extend_from_slice(&tile_bytes.to_le_bytes());
+    }
+    for tile_y in 0..down {
+        for tile_x in 0..across {
+            for y in tile_y * tile..(tile_y + 1) * tile {
+                for x in tile_x * tile..(tile_x + 1) * tile {
+                    match image.get_pixel_checked(x, y) {
+                        Some(pixel) => tiff.extend_from_slice(&pixel.0),
+                        None => tiff.extend_from_slice(&[0, 0, 0]),
+                    }
+                }
+            }
+        }
+    }
+    fs::write(path, tiff)
+}
+
+// runs the command line tool, returning its exit status and what it printed
+fn run_cli(args: &[&str]) -> Result<std::process::Output, std::io::Error> {
+    std::process::Command::new(env!("CARGO_BIN_EXE_iiif-tiler-rust"))
+        .args(args)
+        .output()
 }